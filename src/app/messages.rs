@@ -1,14 +1,61 @@
-use std::{path::PathBuf, result};
+use std::{path::PathBuf, result, time::SystemTime};
+
+use iced::widget::text_editor;
+
+use crate::config::{
+    AuthMethod, FolderTarget, ImageFit, OAuthProvider, PageSize, Profiles, RecipientMode, TlsMode,
+};
+use crate::email::{SendFailure, SendProgress, SentEmail};
+use crate::pdf::{Preview, Validation};
+
+use super::PreparedFiles;
 
 #[derive(Debug, Clone)]
 pub enum Message {
     OpenFiles,
     FilesPicked(Option<Vec<PathBuf>>),
     FilesDropped(Vec<PathBuf>),
+    FileHashed(u64, result::Result<String, String>),
+    FileValidated(u64, Validation),
+    FilePreviewed(u64, Option<SystemTime>, result::Result<Preview, String>),
+    ImagesConverted(result::Result<PathBuf, String>),
+    AllowDuplicatesToggled(bool),
     Send,
-    Sent(result::Result<usize, String>),
-    Remove(PathBuf),
-    EditName(usize),
+    MergeToggled(bool),
+    FilesPrepared(
+        Option<String>,
+        Option<String>,
+        result::Result<PreparedFiles, String>,
+    ),
+    PageRangesChanged(u64, String),
+    SendProgress(u64, SendProgress),
+    Sent(u64, result::Result<SentEmail, SendFailure>),
+    CancelSend,
+    Tick,
+    OpenQueue,
+    CloseQueue,
+    RetryJob(u64),
+    RemoveJob(u64),
+    ClearSentJobs,
+    ToggleWatch(bool),
+    WatchedFileReady(PathBuf),
+    WatchedFileInvalid(PathBuf, Validation),
+    WatchFailed(String),
+    ConfirmWatched(PathBuf),
+    IgnoreWatched(PathBuf),
+    AddWatchFolder,
+    WatchFolderAdded(Option<String>),
+    RemoveWatchFolder(usize),
+    WatchConfirmToggled(bool),
+    OpenHistory,
+    CloseHistory,
+    HistorySearchChanged(String),
+    Resend(u64),
+    ClearHistory,
+    Remove(u64),
+    MoveUp(u64),
+    MoveDown(u64),
+    EditName(u64),
     NameChanged(String),
     CommitEdit,
     CancelEdit,
@@ -20,6 +67,59 @@ pub enum Message {
     FromEmailChanged(String),
     ToEmailChanged(String),
     AppPasswordChanged(String),
+    MaxMessageSizeChanged(String),
+    SubjectTemplateChanged(String),
+    BodyTemplateEdited(text_editor::Action),
+    SubjectOverrideChanged(String),
+    SendFolderSelected(Option<String>),
+    FileFolderSelected(u64, Option<String>),
+    FolderPresetInputChanged(String),
+    AddFolderPreset,
+    RemoveFolderPreset(usize),
+    FolderTargetChanged(FolderTarget),
+    FolderPatternChanged(String),
+    SelectSendProfile(String),
+    SelectEditedProfile(String),
+    ProfileNameChanged(String),
+    RenameProfile,
+    AddProfile,
+    DeleteProfile,
+    SetDefaultProfile,
+    ToggleRecipient(String, bool),
+    AddRecipient,
+    RecipientNameChanged(usize, String),
+    RecipientEmailChanged(usize, String),
+    RemoveRecipient(usize),
+    RecipientModeChanged(RecipientMode),
+    ImagePageSizeChanged(PageSize),
+    CompressionToggled(bool),
+    CompressionDpiChanged(String),
+    CompressionQualityChanged(String),
+    ImageFitChanged(ImageFit),
+    MasterPassphraseChanged(String),
+    UnlockPassphraseChanged(String),
+    Unlock,
+    Unlocked(result::Result<Profiles, String>),
+    AuthMethodChanged(AuthMethod),
+    OAuthProviderChanged(OAuthProvider),
+    OAuthClientIdChanged(String),
+    OAuthClientSecretChanged(String),
+    OAuthAuthUrlChanged(String),
+    OAuthTokenUrlChanged(String),
+    OAuthScopeChanged(String),
+    OAuthSignIn,
+    OAuthSignedIn(String, result::Result<String, String>),
+    OAuthSignOut,
+    TlsModeChanged(TlsMode),
+    CaCertPathChanged(String),
+    PinnedCertPathChanged(String),
+    BrowseCaCert,
+    BrowsePinnedCert,
+    CaCertPicked(Option<String>),
+    PinnedCertPicked(Option<String>),
+    TestConnection,
+    ConnectionTested(result::Result<(), String>),
     SaveSettings,
     SettingsSaved(result::Result<(), String>),
+    RefreshTokensSaved(result::Result<(), String>),
 }
@@ -4,5 +4,5 @@ pub mod view;
 pub mod widgets;
 
 pub use messages::Message;
-pub use state::AppState;
+pub use state::{AppState, FilePreview, PreparedFiles, SelectedFile};
 pub use view::view;
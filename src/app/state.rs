@@ -1,16 +1,342 @@
-use crate::config::{ConfigManager, EmailSettings};
+use crate::config::{ConfigManager, EmailSettings, Profile, Profiles};
+use crate::email::SendProgress;
+use crate::history::History;
+use crate::pdf::{Optimized, Validation};
+use crate::queue::{JobState, Outbox, SendJob};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Rendered first page and details of a file, kept for as long as the file
+/// is unchanged.
+pub struct FilePreview {
+    pub thumbnail: iced::widget::image::Handle,
+    pub pages: usize,
+    pub size: u64,
+    /// Modification time of the file when it was rendered.
+    pub modified: Option<SystemTime>,
+}
+
+impl FilePreview {
+    /// Whether the file at `path` still has the size and modification time
+    /// it was rendered with.
+    pub fn is_current(&self, path: &std::path::Path) -> bool {
+        std::fs::metadata(path)
+            .is_ok_and(|m| m.len() == self.size && m.modified().ok() == self.modified)
+    }
+}
+
+/// Attachments made from the selected files by splitting, compressing or
+/// merging them, ready to be queued.
+#[derive(Debug, Clone)]
+pub struct PreparedFiles {
+    pub attachments: Vec<(PathBuf, String)>,
+    pub optimized: Vec<Optimized>,
+    /// Hashes of the selected files each attachment was made from.
+    pub sources: HashMap<PathBuf, Vec<String>>,
+}
+
+/// A file in the list to send.
+#[derive(Debug, Clone)]
+pub struct SelectedFile {
+    /// Identifies the file in messages for as long as it is in the list,
+    /// wherever it is moved.
+    pub id: u64,
+    pub path: PathBuf,
+    /// Name of the attachment, ending in `.pdf`.
+    pub name: String,
+    /// Size in bytes when added, `None` if it could not be read.
+    pub size: Option<u64>,
+    /// SHA-256 of the contents, `None` until it has been computed.
+    pub hash: Option<String>,
+    /// Result of checking the file, `None` while it is checked.
+    pub check: Option<Validation>,
+    /// Pages to send, e.g. `1-20, 45-60`, empty for all.
+    pub page_ranges: String,
+    /// Destination folder instead of the one picked for the send.
+    pub folder: Option<String>,
+}
 
 #[derive(Default)]
 pub struct AppState {
-    pub selected_files: Vec<PathBuf>,
-    pub file_names: Vec<String>,
+    /// Files to send, in attachment order.
+    pub files: Vec<SelectedFile>,
+    /// Id given to the next added file.
+    pub next_file_id: u64,
+    /// Previews of files added during this run, by path.
+    pub previews: HashMap<PathBuf, FilePreview>,
+    /// Sends files that were already sent to the same address, for the next
+    /// send only.
+    pub allow_duplicates: bool,
+    /// Sends the selected files as a single merged PDF.
+    pub merge_files: bool,
+    /// Subject template for the next send instead of the profile's, empty
+    /// to use the profile's.
+    pub subject_override: String,
+    /// Destination folder picked for the send, see
+    /// [`crate::config::FolderRouting`].
+    pub send_folder: Option<String>,
+    /// Folder preset being typed in the settings view.
+    pub new_folder_preset: String,
+    /// Body template of the edited profile, as shown in the settings view.
+    pub body_template: iced::widget::text_editor::Content,
+    /// Files optimized during the last send because they were too large.
+    pub optimized: Vec<Optimized>,
     pub status: Option<String>,
     pub sending: bool,
-    pub editing_index: Option<usize>,
+    pub sending_job: Option<u64>,
+    /// Aborts the email in flight.
+    pub send_handle: Option<iced::task::Handle>,
+    pub send_progress: Option<SendProgress>,
+    pub sent_count: usize,
+    pub sent_emails: usize,
+    pub outbox: Outbox,
+    pub show_queue: bool,
+    pub watching: bool,
+    /// Files found in watched folders waiting for confirmation.
+    pub watch_prompts: Vec<PathBuf>,
+    pub history: History,
+    pub show_history: bool,
+    pub history_search: String,
+    /// File whose name is being edited.
+    pub editing_file: Option<u64>,
     pub editing_buffer: String,
     pub show_settings: bool,
-    pub settings: EmailSettings,
+    pub profiles: Profiles,
+    pub editing_profile: usize,
+    /// New name typed for the edited profile, applied on Rename.
+    pub profile_name_buffer: Option<String>,
+    pub send_profile: String,
+    pub selected_recipients: Vec<String>,
     pub config_manager: Option<ConfigManager>,
     pub settings_changed: bool,
+    pub testing_connection: bool,
+    pub connection_test: Option<Result<(), String>>,
+    pub signing_in: bool,
+    pub oauth_status: Option<Result<String, String>>,
+    pub master_passphrase: String,
+    pub locked: bool,
+    pub unlock_passphrase: String,
+    pub unlocking: bool,
+}
+
+impl AppState {
+    /// Profile currently open in the settings view.
+    pub fn edited_profile(&self) -> &Profile {
+        &self.profiles.profiles[self.editing_profile]
+    }
+
+    pub fn edited_settings_mut(&mut self) -> &mut EmailSettings {
+        &mut self.profiles.profiles[self.editing_profile].settings
+    }
+
+    /// Shows the edited profile's body template in the body editor.
+    pub fn load_body_template(&mut self) {
+        self.body_template = iced::widget::text_editor::Content::with_text(
+            &self.edited_profile().settings.body_template,
+        );
+    }
+
+    /// Why the edited profile can't be renamed to `name`, if it can't.
+    pub fn profile_rename_error(&self, name: &str) -> Option<&'static str> {
+        let name = name.trim();
+        if name.is_empty() {
+            Some("Profile name can't be empty")
+        } else if self
+            .profiles
+            .profiles
+            .iter()
+            .enumerate()
+            .any(|(i, p)| i != self.editing_profile && p.name == name)
+        {
+            Some("Another profile already has this name")
+        } else {
+            None
+        }
+    }
+
+    /// Addresses the next send goes to: the checked address book entries,
+    /// or the send profile's To Email if none are checked.
+    pub fn destination(&self) -> Vec<String> {
+        let recipients: Vec<String> = self
+            .profiles
+            .address_book
+            .iter()
+            .filter(|r| self.selected_recipients.contains(&r.email))
+            .map(|r| r.email.clone())
+            .collect();
+
+        if recipients.is_empty() {
+            self.send_profile()
+                .map(|p| vec![p.settings.to_email.clone()])
+                .unwrap_or_default()
+        } else {
+            recipients
+        }
+    }
+
+    /// Number of selected files already sent or waiting in the outbox for
+    /// the destination.
+    pub fn duplicate_count(&self) -> usize {
+        let destination = self.destination();
+        self.files
+            .iter()
+            .filter_map(|f| f.hash.as_deref())
+            .filter(|hash| {
+                self.history.find_sent(hash, &destination).is_some()
+                    || self.is_queued(hash, &destination)
+            })
+            .count()
+    }
+
+    /// Whether a pending job in the outbox sends a file with hash `sha256`
+    /// to any of `recipients`.
+    pub fn is_queued(&self, sha256: &str, recipients: &[String]) -> bool {
+        self.outbox.jobs().iter().any(|job| {
+            job.state == JobState::Pending
+                && job.sources.values().flatten().any(|h| h == sha256)
+                && self.job_recipients(job).iter().any(|r| {
+                    recipients
+                        .iter()
+                        .any(|d| d.trim().eq_ignore_ascii_case(r.trim()))
+                })
+        })
+    }
+
+    /// Addresses the email of `job` goes to.
+    fn job_recipients(&self, job: &SendJob) -> Vec<String> {
+        if !job.recipients.is_empty() {
+            return job.recipients.clone();
+        }
+
+        let profile = match &job.profile {
+            Some(name) => self.profiles.get(name),
+            None => self.profiles.default_profile(),
+        };
+        profile
+            .map(|p| vec![p.settings.to_email.clone()])
+            .unwrap_or_default()
+    }
+
+    /// Number of selected files that are encrypted or corrupt.
+    pub fn invalid_count(&self) -> usize {
+        self.files
+            .iter()
+            .filter(|f| f.check.as_ref().is_some_and(|c| !c.is_valid()))
+            .count()
+    }
+
+    pub fn file(&self, id: u64) -> Option<&SelectedFile> {
+        self.files.iter().find(|f| f.id == id)
+    }
+
+    pub fn file_mut(&mut self, id: u64) -> Option<&mut SelectedFile> {
+        self.files.iter_mut().find(|f| f.id == id)
+    }
+
+    /// Position of the file in the list.
+    pub fn file_index(&self, id: u64) -> Option<usize> {
+        self.files.iter().position(|f| f.id == id)
+    }
+
+    /// Subject template entered for this send, if any, clearing it for the
+    /// next one.
+    pub fn take_subject_override(&mut self) -> Option<String> {
+        let subject = std::mem::take(&mut self.subject_override);
+        (!subject.trim().is_empty()).then_some(subject)
+    }
+
+    /// Profile picked in the main view for new sends.
+    pub fn send_profile(&self) -> Option<&Profile> {
+        self.profiles
+            .get(&self.send_profile)
+            .or_else(|| self.profiles.default_profile())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::SentEmail;
+    use crate::history::SentFile;
+
+    /// State sending to the default profile's address, with a selected file
+    /// for each of `hashes`.
+    fn state_with_files(hashes: &[&str]) -> AppState {
+        let mut state = AppState::default();
+        state.profiles.profiles[0].settings.to_email = "ipad@goodnotes.email".to_string();
+        for (i, hash) in hashes.iter().enumerate() {
+            state.files.push(SelectedFile {
+                id: i as u64,
+                path: PathBuf::from(format!("{i}.pdf")),
+                name: format!("{i}.pdf"),
+                size: None,
+                hash: Some(hash.to_string()),
+                check: None,
+                page_ranges: String::new(),
+                folder: None,
+            });
+        }
+        state
+    }
+
+    fn record_sent(state: &mut AppState, recipient: &str, sha256: &str) {
+        let file = SentFile {
+            path: PathBuf::from("sent.pdf"),
+            name: "sent.pdf".to_string(),
+            size: 1,
+            sha256: sha256.to_string(),
+            sources: Vec::new(),
+        };
+        state.history.record(
+            SentEmail {
+                recipients: vec![recipient.to_string()],
+                files: vec![file],
+                subject: "PDF files".to_string(),
+                folder: None,
+                response: "250 OK".to_string(),
+            },
+            None,
+            0,
+        );
+    }
+
+    fn enqueue(state: &mut AppState, recipients: Vec<String>, sha256: &str) -> u64 {
+        let path = PathBuf::from("queued.pdf");
+        let sources = HashMap::from([(path.clone(), vec![sha256.to_string()])]);
+        state.outbox.enqueue(
+            vec![(path, "queued.pdf".to_string())],
+            None,
+            recipients,
+            None,
+            None,
+            sources,
+        )
+    }
+
+    #[test]
+    fn counts_files_already_sent_to_the_destination() {
+        let mut state = state_with_files(&["aaa", "bbb", "ccc"]);
+        record_sent(&mut state, "ipad@goodnotes.email", "aaa");
+        record_sent(&mut state, "mac@goodnotes.email", "bbb");
+
+        assert_eq!(state.duplicate_count(), 1);
+    }
+
+    #[test]
+    fn counts_files_waiting_in_the_outbox() {
+        let mut state = state_with_files(&["aaa", "bbb", "ccc"]);
+        // To the profile's address, and to another one
+        let id = enqueue(&mut state, Vec::new(), "aaa");
+        enqueue(&mut state, vec!["mac@goodnotes.email".to_string()], "bbb");
+
+        assert!(state.is_queued("aaa", &state.destination()));
+        assert_eq!(state.duplicate_count(), 1);
+
+        // A job that gave up no longer counts
+        state
+            .outbox
+            .mark_failed(id, "rejected".to_string(), true, 0);
+        assert_eq!(state.duplicate_count(), 0);
+    }
 }
@@ -1,20 +1,131 @@
-use iced::widget::{button, column, container, row, scrollable, text, text_input};
+use iced::widget::{
+    button, checkbox, column, container, image, pick_list, progress_bar, row, scrollable, text,
+    text_editor, text_input,
+};
 use iced::{Alignment, Background, Border, Color, Element, Length, Shadow, Theme};
 
 use super::widgets::OutsideCommit;
 use super::{AppState, Message};
+use crate::config::{
+    AuthMethod, FolderTarget, ImageFit, OAuthProvider, PageSize, RecipientMode, TlsMode,
+};
+use crate::email;
+use crate::history;
+use crate::pdf::{self, Validation};
+use crate::queue::{self, JobState};
 
 pub fn view(state: &AppState) -> Element<'_, Message> {
-    if state.show_settings {
+    if state.locked {
+        unlock_view(state)
+    } else if state.show_settings {
         settings_view(state)
+    } else if state.show_queue {
+        queue_view(state)
+    } else if state.show_history {
+        history_view(state)
     } else {
         main_view(state)
     }
 }
 
-fn settings_view(state: &AppState) -> Element<'_, Message> {
+fn input_style(_theme: &Theme, _status: text_input::Status) -> text_input::Style {
+    text_input::Style {
+        background: Background::Color(Color::from_rgb8(30, 30, 30)),
+        border: Border {
+            radius: 4.0.into(),
+            width: 1.0,
+            color: Color::from_rgb8(120, 120, 120),
+        },
+        icon: Default::default(),
+        placeholder: Color::from_rgb8(150, 150, 150),
+        value: Color::WHITE,
+        selection: Color::from_rgb8(80, 80, 160),
+    }
+}
+
+fn editor_style(_theme: &Theme, _status: text_editor::Status) -> text_editor::Style {
+    text_editor::Style {
+        background: Background::Color(Color::from_rgb8(30, 30, 30)),
+        border: Border {
+            radius: 4.0.into(),
+            width: 1.0,
+            color: Color::from_rgb8(120, 120, 120),
+        },
+        icon: Default::default(),
+        placeholder: Color::from_rgb8(150, 150, 150),
+        value: Color::WHITE,
+        selection: Color::from_rgb8(80, 80, 160),
+    }
+}
+
+fn hint_style(_theme: &Theme) -> text::Style {
+    text::Style {
+        color: Some(Color::from_rgb8(150, 150, 150)),
+    }
+}
+
+fn small_button_style(_theme: &Theme, status: button::Status) -> button::Style {
+    let base = Color::from_rgb8(80, 80, 80);
+    let hovered = Color::from_rgb8(110, 110, 110);
+
+    let color = match status {
+        button::Status::Hovered => hovered,
+        _ => base,
+    };
+
+    button::Style {
+        background: Some(Background::Color(color)),
+        text_color: Color::WHITE,
+        border: Border {
+            radius: 4.0.into(),
+            width: 0.0,
+            color: Color::TRANSPARENT,
+        },
+        shadow: Shadow::default(),
+    }
+}
+
+fn unlock_view(state: &AppState) -> Element<'_, Message> {
+    let passphrase_input = text_input("Master passphrase", &state.unlock_passphrase)
+        .on_input(Message::UnlockPassphraseChanged)
+        .on_submit(Message::Unlock)
+        .padding(8)
+        .secure(true)
+        .style(input_style);
+
+    let unlock_button = button(text(if state.unlocking {
+        "Unlocking..."
+    } else {
+        "Unlock"
+    }))
+    .style(small_button_style)
+    .on_press_maybe((!state.unlocking).then_some(Message::Unlock))
+    .padding(12);
+
+    let status =
+        text(state.status.as_deref().unwrap_or("")).style(|_theme: &iced::Theme| text::Style {
+            color: Some(Color::from_rgb8(0xD9, 0x2F, 0x2F)),
+        });
+
+    container(
+        column![
+            text("Settings are locked").size(24),
+            text("Enter your master passphrase to unlock them.").size(16),
+            passphrase_input,
+            unlock_button,
+            status,
+        ]
+        .spacing(20)
+        .max_width(400),
+    )
+    .center(Length::Fill)
+    .padding(16)
+    .into()
+}
+
+fn queue_view(state: &AppState) -> Element<'_, Message> {
     let header = row![
-        text("Settings").size(24),
+        text("Queue").size(24),
         container(
             button(text("X"))
                 .style(|_theme: &Theme, status| {
@@ -37,14 +148,173 @@ fn settings_view(state: &AppState) -> Element<'_, Message> {
                         shadow: Shadow::default(),
                     }
                 })
-                .on_press(Message::CloseSettings)
+                .on_press(Message::CloseQueue)
+                .padding(8)
+        )
+        .width(Length::Fill)
+        .align_x(Alignment::End)
+    ];
+
+    let now = queue::now();
+    let jobs_column = if state.outbox.jobs().is_empty() {
+        column![text("The queue is empty")]
+    } else {
+        let mut col = column![];
+        for job in state.outbox.jobs() {
+            let names = job
+                .files
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            let in_flight = state.sending_job == Some(job.id);
+            let (status_text, status_color) = if in_flight {
+                ("Sending...".to_string(), Color::from_rgb8(150, 150, 150))
+            } else {
+                match job.state {
+                    JobState::Sent => ("Sent".to_string(), Color::from_rgb8(0x18, 0x7A, 0x3E)),
+                    JobState::Failed => (
+                        format!(
+                            "Failed: {}",
+                            job.last_error.as_deref().unwrap_or("unknown error")
+                        ),
+                        Color::from_rgb8(0xD9, 0x2F, 0x2F),
+                    ),
+                    JobState::Pending if job.attempts == 0 => {
+                        ("Pending".to_string(), Color::from_rgb8(150, 150, 150))
+                    }
+                    JobState::Pending => (
+                        format!(
+                            "Attempt {} failed, retrying in {}s: {}",
+                            job.attempts,
+                            job.next_attempt.saturating_sub(now),
+                            job.last_error.as_deref().unwrap_or("unknown error")
+                        ),
+                        Color::from_rgb8(0xD9, 0x2F, 0x2F),
+                    ),
+                }
+            };
+
+            let recipients = if job.recipients.is_empty() {
+                String::new()
+            } else {
+                format!(" to {}", job.recipients.join(", "))
+            };
+
+            let info = column![
+                text(format!("{}{}", names, recipients)),
+                text(status_text)
+                    .size(14)
+                    .style(move |_theme: &Theme| text::Style {
+                        color: Some(status_color),
+                    })
+            ]
+            .spacing(2)
+            .width(Length::Fill);
+
+            let mut retry_btn = button(text("Retry")).style(small_button_style).padding(4);
+            if !in_flight && job.state != JobState::Sent {
+                retry_btn = retry_btn.on_press(Message::RetryJob(job.id));
+            }
+
+            let mut remove_btn = button(text("X"))
+                .style(|_theme: &iced::Theme, status| {
+                    let base = Color::from_rgb8(0xD9, 0x2F, 0x2F);
+                    let hovered = Color::from_rgb8(0xE5, 0x46, 0x46);
+
+                    let color = match status {
+                        button::Status::Hovered => hovered,
+                        _ => base,
+                    };
+
+                    button::Style {
+                        background: Some(Background::Color(color)),
+                        text_color: Color::WHITE,
+                        border: Border {
+                            radius: 4.0.into(),
+                            width: 0.0,
+                            color: Color::TRANSPARENT,
+                        },
+                        shadow: Shadow::default(),
+                    }
+                })
+                .padding(4);
+            if !in_flight {
+                remove_btn = remove_btn.on_press(Message::RemoveJob(job.id));
+            }
+
+            col = col.push(
+                row![info, retry_btn, remove_btn]
+                    .spacing(8)
+                    .align_y(Alignment::Center),
+            );
+        }
+
+        col
+    };
+
+    let has_sent = state
+        .outbox
+        .jobs()
+        .iter()
+        .any(|j| j.state == JobState::Sent);
+    let mut clear_sent_btn = button(text("Clear sent"))
+        .style(small_button_style)
+        .padding(8);
+    if has_sent {
+        clear_sent_btn = clear_sent_btn.on_press(Message::ClearSentJobs);
+    }
+
+    column![
+        header,
+        scrollable(jobs_column.spacing(8)).height(Length::Fill),
+        clear_sent_btn,
+    ]
+    .spacing(20)
+    .padding(16)
+    .into()
+}
+
+fn history_view(state: &AppState) -> Element<'_, Message> {
+    let header = row![
+        text("History").size(24),
+        container(
+            button(text("X"))
+                .style(|_theme: &Theme, status| {
+                    let base = Color::from_rgb8(80, 80, 80);
+                    let hovered = Color::from_rgb8(110, 110, 110);
+
+                    let color = match status {
+                        button::Status::Hovered => hovered,
+                        _ => base,
+                    };
+
+                    button::Style {
+                        background: Some(Background::Color(color)),
+                        text_color: Color::WHITE,
+                        border: Border {
+                            radius: 4.0.into(),
+                            width: 0.0,
+                            color: Color::TRANSPARENT,
+                        },
+                        shadow: Shadow::default(),
+                    }
+                })
+                .on_press(Message::CloseHistory)
                 .padding(8)
         )
         .width(Length::Fill)
         .align_x(Alignment::End)
     ];
 
-    let input_style = |_theme: &iced::Theme, _status| text_input::Style {
+    let search_input = text_input(
+        "Search by file, hash, recipient or profile",
+        &state.history_search,
+    )
+    .on_input(Message::HistorySearchChanged)
+    .padding(8)
+    .style(|_theme: &iced::Theme, _status| text_input::Style {
         background: Background::Color(Color::from_rgb8(30, 30, 30)),
         border: Border {
             radius: 4.0.into(),
@@ -55,11 +325,210 @@ fn settings_view(state: &AppState) -> Element<'_, Message> {
         placeholder: Color::from_rgb8(150, 150, 150),
         value: Color::WHITE,
         selection: Color::from_rgb8(80, 80, 160),
+    });
+
+    // Newest first
+    let entries: Vec<_> = state
+        .history
+        .entries()
+        .iter()
+        .rev()
+        .filter(|e| e.matches(&state.history_search))
+        .collect();
+
+    let entries_column = if state.history.entries().is_empty() {
+        column![text("Nothing has been sent yet")]
+    } else if entries.is_empty() {
+        column![text("No sent emails match the search")]
+    } else {
+        let mut col = column![];
+        for entry in entries {
+            let mut files = column![].spacing(2);
+            for file in &entry.files {
+                files = files.push(
+                    text(format!(
+                        "{} ({:.1} MB, SHA-256 {})",
+                        file.name,
+                        file.size as f64 / (1024.0 * 1024.0),
+                        &file.sha256[..file.sha256.len().min(12)]
+                    ))
+                    .size(14),
+                );
+            }
+
+            let mut details = format!(
+                "{} to {}",
+                history::format_timestamp(entry.timestamp),
+                entry.recipients.join(", ")
+            );
+            if let Some(profile) = &entry.profile {
+                details.push_str(&format!(" with {}", profile));
+            }
+
+            let subject = (!entry.subject.is_empty()).then(|| {
+                let subject = match &entry.folder {
+                    Some(folder) => format!("\"{}\" in folder {}", entry.subject, folder),
+                    None => format!("\"{}\"", entry.subject),
+                };
+                text(subject).size(14)
+            });
+
+            let info = column![text(details)]
+                .push_maybe(subject)
+                .push(files)
+                .push(
+                    text(format!("Server replied: {}", entry.response))
+                        .size(12)
+                        .style(|_theme: &Theme| text::Style {
+                            color: Some(Color::from_rgb8(150, 150, 150)),
+                        }),
+                )
+                .spacing(2)
+                .width(Length::Fill);
+
+            let resend: Element<'_, Message> = if entry.has_prepared_files() {
+                text("Split, merged or compressed,\nadd the files again to resend")
+                    .size(12)
+                    .style(hint_style)
+                    .into()
+            } else {
+                button(text("Resend"))
+                    .style(small_button_style)
+                    .padding(4)
+                    .on_press_maybe(entry.files_exist().then_some(Message::Resend(entry.id)))
+                    .into()
+            };
+
+            col = col.push(row![info, resend].spacing(8).align_y(Alignment::Center));
+        }
+
+        col
     };
 
+    let mut clear_btn = button(text("Clear history"))
+        .style(small_button_style)
+        .padding(8);
+    if !state.history.entries().is_empty() {
+        clear_btn = clear_btn.on_press(Message::ClearHistory);
+    }
+
+    column![
+        header,
+        search_input,
+        scrollable(entries_column.spacing(12)).height(Length::Fill),
+        clear_btn,
+    ]
+    .spacing(20)
+    .padding(16)
+    .into()
+}
+
+fn settings_view(state: &AppState) -> Element<'_, Message> {
+    let header = row![
+        text("Settings").size(24),
+        container(
+            button(text("X"))
+                .style(|_theme: &Theme, status| {
+                    let base = Color::from_rgb8(80, 80, 80);
+                    let hovered = Color::from_rgb8(110, 110, 110);
+
+                    let color = match status {
+                        button::Status::Hovered => hovered,
+                        _ => base,
+                    };
+
+                    button::Style {
+                        background: Some(Background::Color(color)),
+                        text_color: Color::WHITE,
+                        border: Border {
+                            radius: 4.0.into(),
+                            width: 0.0,
+                            color: Color::TRANSPARENT,
+                        },
+                        shadow: Shadow::default(),
+                    }
+                })
+                .on_press(Message::CloseSettings)
+                .padding(8)
+        )
+        .width(Length::Fill)
+        .align_x(Alignment::End)
+    ];
+
+    let profile = state.edited_profile();
+    let settings = &profile.settings;
+
+    let is_default = state.profiles.default == profile.name;
+    let mut default_btn = button(text(if is_default {
+        "Default"
+    } else {
+        "Set as default"
+    }))
+    .style(small_button_style)
+    .padding(8);
+    if !is_default {
+        default_btn = default_btn.on_press(Message::SetDefaultProfile);
+    }
+
+    let mut delete_btn = button(text("Delete")).style(small_button_style).padding(8);
+    if state.profiles.profiles.len() > 1 {
+        delete_btn = delete_btn.on_press(Message::DeleteProfile);
+    }
+
+    let profile_selector = column![
+        text("Profile").size(14),
+        row![
+            pick_list(
+                state.profiles.names(),
+                Some(profile.name.clone()),
+                Message::SelectEditedProfile
+            )
+            .width(Length::Fill)
+            .padding(8),
+            button(text("Add"))
+                .style(small_button_style)
+                .on_press(Message::AddProfile)
+                .padding(8),
+            default_btn,
+            delete_btn,
+        ]
+        .spacing(8)
+    ]
+    .spacing(4);
+
+    let profile_name = state
+        .profile_name_buffer
+        .as_deref()
+        .unwrap_or(&profile.name);
+    let rename_error = state.profile_rename_error(profile_name);
+
+    let mut rename_btn = button(text("Rename")).style(small_button_style).padding(8);
+    if rename_error.is_none() && profile_name != profile.name {
+        rename_btn = rename_btn.on_press(Message::RenameProfile);
+    }
+
+    let profile_name_input = column![
+        text("Profile Name").size(14),
+        row![
+            text_input("e.g. Work", profile_name)
+                .on_input(Message::ProfileNameChanged)
+                .on_submit(Message::RenameProfile)
+                .padding(8)
+                .style(input_style),
+            rename_btn,
+        ]
+        .spacing(8),
+    ]
+    .push_maybe(rename_error.map(|e| {
+        text(e).size(12).style(|_theme: &iced::Theme| text::Style {
+            color: Some(Color::from_rgb8(0xD9, 0x2F, 0x2F)),
+        })
+    }))
+    .spacing(4);
+
     let smtp_host_input = column![
         text("SMTP Host").size(14),
-        text_input("e.g. smtp.gmail.com", &state.settings.smtp_host)
+        text_input("e.g. smtp.gmail.com", &settings.smtp_host)
             .on_input(Message::SmtpHostChanged)
             .padding(8)
             .style(input_style)
@@ -68,16 +537,67 @@ fn settings_view(state: &AppState) -> Element<'_, Message> {
 
     let smtp_port_input = column![
         text("SMTP Port").size(14),
-        text_input("e.g. 587", &state.settings.smtp_port)
+        text_input("e.g. 587", &settings.smtp_port)
             .on_input(Message::SmtpPortChanged)
             .padding(8)
             .style(input_style)
     ]
     .spacing(4);
 
+    let tls_mode_input = column![
+        text("Encryption").size(14),
+        pick_list(
+            TlsMode::ALL,
+            Some(settings.tls_mode),
+            Message::TlsModeChanged
+        )
+        .padding(8)
+    ]
+    .spacing(4);
+
+    let ca_cert_input = column![
+        text("Custom CA Certificate (optional)").size(14),
+        row![
+            text_input("Path to a PEM file", &settings.ca_cert_path)
+                .on_input(Message::CaCertPathChanged)
+                .padding(8)
+                .style(input_style),
+            button(text("Browse..."))
+                .style(small_button_style)
+                .on_press(Message::BrowseCaCert)
+                .padding(8),
+        ]
+        .spacing(8)
+    ]
+    .spacing(4);
+
+    let pinned_cert_input = column![
+        text("Pinned Server Certificate (optional)").size(14),
+        row![
+            text_input(
+                "Path to the server's self-signed PEM certificate",
+                &settings.pinned_cert_path
+            )
+            .on_input(Message::PinnedCertPathChanged)
+            .padding(8)
+            .style(input_style),
+            button(text("Browse..."))
+                .style(small_button_style)
+                .on_press(Message::BrowsePinnedCert)
+                .padding(8),
+        ]
+        .spacing(8),
+        text("Only this certificate is trusted and the host name is not checked")
+            .size(12)
+            .style(|_theme: &Theme| text::Style {
+                color: Some(Color::from_rgb8(150, 150, 150)),
+            }),
+    ]
+    .spacing(4);
+
     let from_email_input = column![
         text("From Email").size(14),
-        text_input("your.email@gmail.com", &state.settings.from_email)
+        text_input("your.email@gmail.com", &settings.from_email)
             .on_input(Message::FromEmailChanged)
             .padding(8)
             .style(input_style)
@@ -86,23 +606,417 @@ fn settings_view(state: &AppState) -> Element<'_, Message> {
 
     let to_email_input = column![
         text("To Email (GoodNotes)").size(14),
-        text_input("your.goodnotes@email", &state.settings.to_email)
+        text_input("your.goodnotes@email", &settings.to_email)
             .on_input(Message::ToEmailChanged)
             .padding(8)
             .style(input_style)
     ]
     .spacing(4);
 
-    let app_password_input = column![
-        text("App Password").size(14),
-        text_input("App-specific password", &state.settings.app_password)
-            .on_input(Message::AppPasswordChanged)
+    let auth_method_input = column![
+        text("Authentication").size(14),
+        pick_list(
+            AuthMethod::ALL,
+            Some(settings.auth_method),
+            Message::AuthMethodChanged
+        )
+        .padding(8)
+    ]
+    .spacing(4);
+
+    let credentials_input: Element<'_, Message> = match settings.auth_method {
+        AuthMethod::Password => column![
+            text("App Password").size(14),
+            text_input("App-specific password", &settings.app_password)
+                .on_input(Message::AppPasswordChanged)
+                .padding(8)
+                .secure(true)
+                .style(input_style)
+        ]
+        .spacing(4)
+        .into(),
+        AuthMethod::OAuth2 => {
+            let oauth = &settings.oauth;
+
+            let signed_in = !oauth.refresh_token.is_empty();
+            let mut sign_in_btn = button(text(if state.signing_in {
+                "Signing in..."
+            } else if signed_in {
+                "Sign in again"
+            } else {
+                "Sign in"
+            }))
+            .style(small_button_style)
+            .padding(8);
+            if !state.signing_in {
+                sign_in_btn = sign_in_btn.on_press(Message::OAuthSignIn);
+            }
+
+            let mut sign_out_btn = button(text("Sign out"))
+                .style(small_button_style)
+                .padding(8);
+            if signed_in && !state.signing_in {
+                sign_out_btn = sign_out_btn.on_press(Message::OAuthSignOut);
+            }
+
+            let sign_in_status = match &state.oauth_status {
+                Some(Ok(message)) => text(message.clone()),
+                Some(Err(e)) => {
+                    text(format!("Error: {}", e)).style(|_theme: &iced::Theme| text::Style {
+                        color: Some(Color::from_rgb8(0xD9, 0x2F, 0x2F)),
+                    })
+                }
+                None if signed_in => text("Signed in"),
+                None => text("Not signed in"),
+            };
+
+            column![
+                column![
+                    text("OAuth2 Provider").size(14),
+                    pick_list(
+                        OAuthProvider::ALL,
+                        Some(oauth.provider),
+                        Message::OAuthProviderChanged
+                    )
+                    .padding(8)
+                ]
+                .spacing(4),
+                column![
+                    text("Client ID").size(14),
+                    text_input("OAuth2 client ID", &oauth.client_id)
+                        .on_input(Message::OAuthClientIdChanged)
+                        .padding(8)
+                        .style(input_style)
+                ]
+                .spacing(4),
+                column![
+                    text("Client Secret").size(14),
+                    text_input(
+                        "Leave empty if the provider doesn't issue one",
+                        &oauth.client_secret
+                    )
+                    .on_input(Message::OAuthClientSecretChanged)
+                    .padding(8)
+                    .secure(true)
+                    .style(input_style)
+                ]
+                .spacing(4),
+                column![
+                    text("Authorization URL").size(14),
+                    text_input("https://...", &oauth.auth_url)
+                        .on_input(Message::OAuthAuthUrlChanged)
+                        .padding(8)
+                        .style(input_style)
+                ]
+                .spacing(4),
+                column![
+                    text("Token URL").size(14),
+                    text_input("https://...", &oauth.token_url)
+                        .on_input(Message::OAuthTokenUrlChanged)
+                        .padding(8)
+                        .style(input_style)
+                ]
+                .spacing(4),
+                column![
+                    text("Scope").size(14),
+                    text_input("e.g. https://mail.google.com/", &oauth.scope)
+                        .on_input(Message::OAuthScopeChanged)
+                        .padding(8)
+                        .style(input_style)
+                ]
+                .spacing(4),
+                row![sign_in_btn, sign_out_btn, sign_in_status]
+                    .spacing(12)
+                    .align_y(Alignment::Center),
+            ]
+            .spacing(20)
+            .into()
+        }
+    };
+
+    let max_message_size_input = column![
+        text("Max Message Size (MB)").size(14),
+        text_input("e.g. 25", &settings.max_message_size_mb)
+            .on_input(Message::MaxMessageSizeChanged)
             .padding(8)
-            .secure(true)
             .style(input_style)
     ]
     .spacing(4);
 
+    // Rendered for the selected files, or an example file
+    let mut preview_names: Vec<String> = state.files.iter().map(|f| f.name.clone()).collect();
+    if preview_names.is_empty() {
+        preview_names.push("Notes.pdf".to_string());
+    }
+    let now = queue::now();
+    let subject_preview = email::render_template(&settings.subject_template, &preview_names, now);
+    let body_preview = email::render_template(&settings.body_template, &preview_names, now);
+
+    let email_content = column![
+        text("Email Content").size(14),
+        text("Placeholders: {count}, {names}, {date} (UTC), {hostname}")
+            .size(12)
+            .style(hint_style),
+        text_input("Subject", &settings.subject_template)
+            .on_input(Message::SubjectTemplateChanged)
+            .padding(8)
+            .style(input_style),
+        text_editor(&state.body_template)
+            .placeholder("Body (optional)")
+            .on_action(Message::BodyTemplateEdited)
+            .height(80)
+            .padding(8)
+            .style(editor_style),
+        text(format!("Subject: {}", subject_preview))
+            .size(12)
+            .style(hint_style),
+    ]
+    .push_maybe((!body_preview.trim().is_empty()).then(|| {
+        text(format!("Body: {}", body_preview))
+            .size(12)
+            .style(hint_style)
+    }))
+    .spacing(4);
+
+    let master_passphrase_input = column![
+        text("Master Passphrase (optional)").size(14),
+        text_input(
+            "Leave empty to unlock automatically",
+            &state.master_passphrase
+        )
+        .on_input(Message::MasterPassphraseChanged)
+        .padding(8)
+        .secure(true)
+        .style(input_style),
+        text("If set, it is asked for every time the app starts")
+            .size(12)
+            .style(|_theme: &Theme| text::Style {
+                color: Some(Color::from_rgb8(150, 150, 150)),
+            }),
+    ]
+    .spacing(4);
+
+    let recipient_mode_input = column![
+        text("Multiple Recipients").size(14),
+        pick_list(
+            RecipientMode::ALL,
+            Some(settings.recipient_mode),
+            Message::RecipientModeChanged
+        )
+        .padding(8)
+    ]
+    .spacing(4);
+
+    let mut address_book = column![
+        text("Address Book").size(14),
+        text("GoodNotes inbox addresses to choose from when sending, shared by all profiles")
+            .size(12)
+            .style(|_theme: &Theme| text::Style {
+                color: Some(Color::from_rgb8(150, 150, 150)),
+            }),
+    ]
+    .spacing(4);
+    for (i, recipient) in state.profiles.address_book.iter().enumerate() {
+        address_book = address_book.push(
+            row![
+                text_input("Name, e.g. iPad", &recipient.name)
+                    .on_input(move |v| Message::RecipientNameChanged(i, v))
+                    .padding(8)
+                    .style(input_style)
+                    .width(Length::FillPortion(1)),
+                text_input("goodnotes@email", &recipient.email)
+                    .on_input(move |v| Message::RecipientEmailChanged(i, v))
+                    .padding(8)
+                    .style(input_style)
+                    .width(Length::FillPortion(2)),
+                button(text("X"))
+                    .style(small_button_style)
+                    .on_press(Message::RemoveRecipient(i))
+                    .padding(8),
+            ]
+            .spacing(8),
+        );
+    }
+    address_book = address_book.push(
+        button(text("Add address"))
+            .style(small_button_style)
+            .on_press(Message::AddRecipient)
+            .padding(8),
+    );
+
+    let image_conversion = column![
+        text("Images").size(14),
+        text("PNG and JPEG files are turned into a PDF with one page per image")
+            .size(12)
+            .style(|_theme: &Theme| text::Style {
+                color: Some(Color::from_rgb8(150, 150, 150)),
+            }),
+        row![
+            pick_list(
+                PageSize::ALL,
+                Some(state.profiles.image_page_size),
+                Message::ImagePageSizeChanged
+            )
+            .padding(8),
+            pick_list(
+                ImageFit::ALL,
+                Some(state.profiles.image_fit),
+                Message::ImageFitChanged
+            )
+            .padding(8),
+        ]
+        .spacing(8),
+    ]
+    .spacing(4);
+
+    let compression = column![
+        text("Compression").size(14),
+        text("PDFs over the message size limit are shrunk by downsampling their images")
+            .size(12)
+            .style(|_theme: &Theme| text::Style {
+                color: Some(Color::from_rgb8(150, 150, 150)),
+            }),
+        checkbox(
+            "Compress oversized PDFs",
+            state.profiles.compression.enabled
+        )
+        .on_toggle(Message::CompressionToggled),
+        row![
+            text_input("Image DPI", &state.profiles.compression.dpi)
+                .on_input(Message::CompressionDpiChanged)
+                .padding(8),
+            text_input("JPEG quality (1-100)", &state.profiles.compression.quality)
+                .on_input(Message::CompressionQualityChanged)
+                .padding(8),
+        ]
+        .spacing(8),
+    ]
+    .spacing(4);
+
+    let routing = &state.profiles.folder_routing;
+    let mut destination_folders = column![
+        text("Destination Folders").size(14),
+        text("Folders a send can be tagged with for GoodNotes to sort it, shared by all profiles")
+            .size(12)
+            .style(hint_style),
+    ]
+    .spacing(4);
+    for (i, folder) in routing.presets.iter().enumerate() {
+        destination_folders = destination_folders.push(
+            row![
+                text(folder).width(Length::Fill),
+                button(text("X"))
+                    .style(small_button_style)
+                    .on_press(Message::RemoveFolderPreset(i))
+                    .padding(8),
+            ]
+            .spacing(8)
+            .align_y(Alignment::Center),
+        );
+    }
+    let example = match routing.target {
+        FolderTarget::Subject => routing.apply("Biology", &subject_preview),
+        FolderTarget::FileName => routing.apply_to_name("Biology", &preview_names[0]),
+    };
+    destination_folders = destination_folders
+        .push(
+            row![
+                text_input("Folder name", &state.new_folder_preset)
+                    .on_input(Message::FolderPresetInputChanged)
+                    .on_submit(Message::AddFolderPreset)
+                    .padding(8)
+                    .style(input_style),
+                button(text("Add folder"))
+                    .style(small_button_style)
+                    .on_press(Message::AddFolderPreset)
+                    .padding(8),
+            ]
+            .spacing(8),
+        )
+        .push(
+            row![
+                pick_list(
+                    FolderTarget::ALL,
+                    Some(routing.target),
+                    Message::FolderTargetChanged
+                )
+                .padding(8),
+                text_input("Pattern, e.g. [{folder}] {text}", &routing.pattern)
+                    .on_input(Message::FolderPatternChanged)
+                    .padding(8)
+                    .style(input_style),
+            ]
+            .spacing(8),
+        )
+        .push(
+            text(format!("Example: {}", example))
+                .size(12)
+                .style(hint_style),
+        );
+
+    let mut watch_folders = column![
+        text("Watched Folders").size(14),
+        text(
+            "New PDFs saved in these folders are sent while watching is on, shared by all profiles"
+        )
+        .size(12)
+        .style(|_theme: &Theme| text::Style {
+            color: Some(Color::from_rgb8(150, 150, 150)),
+        }),
+    ]
+    .spacing(4);
+    for (i, folder) in state.profiles.watch_folders.iter().enumerate() {
+        watch_folders = watch_folders.push(
+            row![
+                text(folder).width(Length::Fill),
+                button(text("X"))
+                    .style(small_button_style)
+                    .on_press(Message::RemoveWatchFolder(i))
+                    .padding(8),
+            ]
+            .spacing(8)
+            .align_y(Alignment::Center),
+        );
+    }
+    watch_folders = watch_folders
+        .push(
+            button(text("Add folder"))
+                .style(small_button_style)
+                .on_press(Message::AddWatchFolder)
+                .padding(8),
+        )
+        .push(
+            checkbox("Ask before sending new files", state.profiles.watch_confirm)
+                .on_toggle(Message::WatchConfirmToggled),
+        );
+
+    let mut test_button = button(text(if state.testing_connection {
+        "Testing..."
+    } else {
+        "Test connection"
+    }))
+    .style(small_button_style)
+    .padding(8);
+    if !state.testing_connection {
+        test_button = test_button.on_press(Message::TestConnection);
+    }
+
+    let test_result = match &state.connection_test {
+        Some(Ok(())) => {
+            text("Connection and login succeeded").style(|_theme: &iced::Theme| text::Style {
+                color: Some(Color::from_rgb8(0x18, 0x7A, 0x3E)),
+            })
+        }
+        Some(Err(e)) => text(format!("Error: {}", e)).style(|_theme: &iced::Theme| text::Style {
+            color: Some(Color::from_rgb8(0xD9, 0x2F, 0x2F)),
+        }),
+        None => text(""),
+    };
+
+    let connection_test = row![test_button, test_result]
+        .spacing(12)
+        .align_y(Alignment::Center);
+
     let save_button = button(text("Save Settings"))
         .style(|_theme: &iced::Theme, status| {
             let base = Color::from_rgb8(34, 139, 34);
@@ -130,11 +1044,27 @@ fn settings_view(state: &AppState) -> Element<'_, Message> {
     let content = column![
         header,
         text("Configure your email settings:").size(16),
+        profile_selector,
+        profile_name_input,
         smtp_host_input,
         smtp_port_input,
+        tls_mode_input,
+        ca_cert_input,
+        pinned_cert_input,
         from_email_input,
         to_email_input,
-        app_password_input,
+        auth_method_input,
+        credentials_input,
+        connection_test,
+        max_message_size_input,
+        email_content,
+        recipient_mode_input,
+        address_book,
+        image_conversion,
+        compression,
+        destination_folders,
+        watch_folders,
+        master_passphrase_input,
         save_button,
     ]
     .spacing(20)
@@ -143,12 +1073,63 @@ fn settings_view(state: &AppState) -> Element<'_, Message> {
     scrollable(content).into()
 }
 
+/// Entry of the destination folder pick lists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FolderChoice {
+    /// No folder for the whole send.
+    NoFolder,
+    /// Whatever folder the send goes to, for a single file.
+    SameAsSend,
+    Preset(String),
+}
+
+impl FolderChoice {
+    fn from_folder(folder: Option<&String>, unset: FolderChoice) -> Self {
+        folder.map_or(unset, |f| FolderChoice::Preset(f.clone()))
+    }
+
+    fn folder(self) -> Option<String> {
+        match self {
+            FolderChoice::Preset(folder) => Some(folder),
+            _ => None,
+        }
+    }
+
+    /// `unset` followed by every preset.
+    fn options(state: &AppState, unset: FolderChoice) -> Vec<FolderChoice> {
+        std::iter::once(unset)
+            .chain(
+                state
+                    .profiles
+                    .folder_routing
+                    .presets
+                    .iter()
+                    .cloned()
+                    .map(FolderChoice::Preset),
+            )
+            .collect()
+    }
+}
+
+impl std::fmt::Display for FolderChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FolderChoice::NoFolder => write!(f, "No folder"),
+            FolderChoice::SameAsSend => write!(f, "Send folder"),
+            FolderChoice::Preset(folder) => write!(f, "{}", folder),
+        }
+    }
+}
+
+/// Height of the first page thumbnails in the file list.
+const THUMBNAIL_HEIGHT: f32 = 64.0;
+
 fn main_view(state: &AppState) -> Element<'_, Message> {
     // List of selected files (or placeholder text)
-    let files_column = if state.selected_files.is_empty() {
+    let files_column = if state.files.is_empty() {
         column![
             text("No PDF files selected"),
-            text("Press 'Open file(s)' or drag and drop PDF files here")
+            text("Press 'Open file(s)' or drag and drop PDF or image files here")
                 .size(14)
                 .style(|_theme: &Theme| text::Style {
                     color: Some(Color::from_rgb8(150, 150, 150)),
@@ -156,9 +1137,38 @@ fn main_view(state: &AppState) -> Element<'_, Message> {
         ]
         .spacing(4)
     } else {
+        let destination = state.destination();
+        let move_button_style = |_theme: &iced::Theme, status| {
+            let color = match status {
+                button::Status::Hovered => Color::from_rgb8(110, 110, 110),
+                button::Status::Disabled => Color::from_rgb8(50, 50, 50),
+                _ => Color::from_rgb8(80, 80, 80),
+            };
+
+            button::Style {
+                background: Some(Background::Color(color)),
+                text_color: Color::WHITE,
+                border: Border {
+                    radius: 4.0.into(),
+                    width: 0.0,
+                    color: Color::TRANSPARENT,
+                },
+                shadow: Shadow::default(),
+            }
+        };
         let mut col = column![];
-        for (i, f) in state.selected_files.iter().enumerate() {
-            let is_editing = state.editing_index == Some(i);
+        for (i, f) in state.files.iter().enumerate() {
+            let id = f.id;
+            let is_editing = state.editing_file == Some(id);
+            let preview = state.previews.get(&f.path);
+            let duplicate = f
+                .hash
+                .as_ref()
+                .and_then(|hash| state.history.find_sent(hash, &destination));
+            let queued = f
+                .hash
+                .as_ref()
+                .is_some_and(|hash| state.is_queued(hash, &destination));
 
             let name_widget: Element<'_, Message> = if is_editing {
                 let input = text_input("File name (without .pdf)", &state.editing_buffer)
@@ -206,10 +1216,110 @@ fn main_view(state: &AppState) -> Element<'_, Message> {
 
                 row![input, cancel].spacing(4).into()
             } else {
-                text(&state.file_names[i]).into()
+                let (check, check_color) = match &f.check {
+                    None => ("Checking...".to_string(), Color::from_rgb8(150, 150, 150)),
+                    Some(Validation::Valid) => {
+                        ("Valid PDF".to_string(), Color::from_rgb8(0x18, 0x7A, 0x3E))
+                    }
+                    Some(Validation::Encrypted) => (
+                        "Encrypted, remove the password to send it".to_string(),
+                        Color::from_rgb8(0xD9, 0x2F, 0x2F),
+                    ),
+                    Some(Validation::Corrupt(reason)) => (
+                        format!("Corrupt: {}", reason),
+                        Color::from_rgb8(0xD9, 0x2F, 0x2F),
+                    ),
+                };
+
+                let pages = preview
+                    .map(|p| format!("{} page{}", p.pages, if p.pages == 1 { "" } else { "s" }));
+                let size = f
+                    .size
+                    .map(|size| format!("{:.1} MB", size as f64 / (1024.0 * 1024.0)));
+                let details = (pages.is_some() || size.is_some()).then(|| {
+                    text(pages.into_iter().chain(size).collect::<Vec<_>>().join(", "))
+                        .size(12)
+                        .style(|_theme: &Theme| text::Style {
+                            color: Some(Color::from_rgb8(150, 150, 150)),
+                        })
+                });
+
+                let mut name_column = column![text(&f.name)]
+                    .push_maybe(details)
+                    .push(
+                        text(check)
+                            .size(12)
+                            .style(move |_theme: &Theme| text::Style {
+                                color: Some(check_color),
+                            }),
+                    )
+                    .spacing(2);
+                if let Some(entry) = duplicate {
+                    name_column = name_column.push(
+                        text(format!(
+                            "Already sent to {} on {}",
+                            entry.recipients.join(", "),
+                            history::format_timestamp(entry.timestamp)
+                        ))
+                        .size(12)
+                        .style(|_theme: &Theme| text::Style {
+                            color: Some(Color::from_rgb8(0xE0, 0x9B, 0x20)),
+                        }),
+                    );
+                } else if queued {
+                    name_column = name_column.push(
+                        text("Already waiting in the queue for this address")
+                            .size(12)
+                            .style(|_theme: &Theme| text::Style {
+                                color: Some(Color::from_rgb8(0xE0, 0x9B, 0x20)),
+                            }),
+                    );
+                }
+                // Files optimized to fit under the size limit on the last send
+                for optimized in state.optimized.iter().filter(|o| o.source == f.path) {
+                    name_column = name_column.push(
+                        text(format!(
+                            "Compressed {}: {:.1} MB -> {:.1} MB",
+                            optimized.name,
+                            optimized.before as f64 / (1024.0 * 1024.0),
+                            optimized.after as f64 / (1024.0 * 1024.0)
+                        ))
+                        .size(12)
+                        .style(|_theme: &Theme| text::Style {
+                            color: Some(Color::from_rgb8(150, 150, 150)),
+                        }),
+                    );
+                }
+                name_column.into()
             };
 
             let name_container = iced::widget::container(name_widget).width(Length::Fill);
+
+            // Pages to send, outlined in red while they can't be parsed
+            let ranges = &f.page_ranges;
+            let ranges_valid = pdf::parse_page_ranges(ranges).is_ok();
+            let ranges_input = text_input("All pages", ranges)
+                .on_input(move |v| Message::PageRangesChanged(id, v))
+                .padding(4)
+                .size(14)
+                .width(140)
+                .style(move |_theme: &iced::Theme, _status| text_input::Style {
+                    background: Background::Color(Color::from_rgb8(30, 30, 30)),
+                    border: Border {
+                        radius: 4.0.into(),
+                        width: 1.0,
+                        color: if ranges_valid {
+                            Color::from_rgb8(120, 120, 120)
+                        } else {
+                            Color::from_rgb8(0xD9, 0x2F, 0x2F)
+                        },
+                    },
+                    icon: Default::default(),
+                    placeholder: Color::from_rgb8(150, 150, 150),
+                    value: Color::WHITE,
+                    selection: Color::from_rgb8(80, 80, 160),
+                });
+
             let remove_btn = button(text("X"))
                 .style(|_theme: &iced::Theme, status| {
                     let base = Color::from_rgb8(0xD9, 0x2F, 0x2F);
@@ -233,10 +1343,54 @@ fn main_view(state: &AppState) -> Element<'_, Message> {
                         shadow: iced::Shadow::default(),
                     }
                 })
-                .on_press(Message::Remove(f.clone()));
+                .on_press(Message::Remove(id));
+
+            // First page, or an empty frame until it has been rendered
+            let thumbnail: Element<'_, Message> = match preview {
+                Some(p) => image(p.thumbnail.clone()).height(THUMBNAIL_HEIGHT).into(),
+                None => container(text(""))
+                    .width(THUMBNAIL_HEIGHT * 0.75)
+                    .height(THUMBNAIL_HEIGHT)
+                    .style(|_theme: &Theme| container::Style {
+                        background: Some(Background::Color(Color::from_rgb8(40, 40, 40))),
+                        ..Default::default()
+                    })
+                    .into(),
+            };
+
+            // Attachments are sent in list order
+            let mut up_btn = button(text("↑")).style(move_button_style).padding([4, 8]);
+            if i > 0 {
+                up_btn = up_btn.on_press(Message::MoveUp(id));
+            }
+            let mut down_btn = button(text("↓")).style(move_button_style).padding([4, 8]);
+            if i + 1 < state.files.len() {
+                down_btn = down_btn.on_press(Message::MoveDown(id));
+            }
+
+            // Folder of this file when it differs from the send's
+            let folder_picker = (!state.profiles.folder_routing.presets.is_empty()).then(|| {
+                pick_list(
+                    FolderChoice::options(state, FolderChoice::SameAsSend),
+                    Some(FolderChoice::from_folder(
+                        f.folder.as_ref(),
+                        FolderChoice::SameAsSend,
+                    )),
+                    move |choice| Message::FileFolderSelected(id, choice.folder()),
+                )
+                .padding(4)
+                .text_size(14)
+                .width(140)
+            });
+
+            let base_row = row![thumbnail, name_container]
+                .push_maybe(folder_picker)
+                .push(ranges_input)
+                .push(column![up_btn, down_btn].spacing(2))
+                .push(remove_btn)
+                .spacing(8);
 
             // Clicking the row when not editing activates edit mode
-            let base_row = row![name_container, remove_btn].spacing(8);
             let row_item: iced::Element<'_, Message> = if !is_editing {
                 let edit_btn = button(base_row)
                     .style(|_theme: &iced::Theme, _status| button::Style {
@@ -249,7 +1403,7 @@ fn main_view(state: &AppState) -> Element<'_, Message> {
                         },
                         shadow: Shadow::default(),
                     })
-                    .on_press(Message::EditName(i))
+                    .on_press(Message::EditName(id))
                     .padding(0);
 
                 edit_btn.into()
@@ -264,14 +1418,120 @@ fn main_view(state: &AppState) -> Element<'_, Message> {
     };
 
     let file_list = scrollable(files_column.spacing(4)).height(Length::Fill);
+
+    // Files from watched folders waiting to be confirmed
+    let mut watch_prompts = column![].spacing(4);
+    for path in &state.watch_prompts {
+        watch_prompts = watch_prompts.push(
+            row![
+                text(format!("New file: {}", crate::default_display_name(path)))
+                    .width(Length::Fill),
+                button(text("Send"))
+                    .style(small_button_style)
+                    .on_press(Message::ConfirmWatched(path.clone()))
+                    .padding(4),
+                button(text("Ignore"))
+                    .style(small_button_style)
+                    .on_press(Message::IgnoreWatched(path.clone()))
+                    .padding(4),
+            ]
+            .spacing(8)
+            .align_y(Alignment::Center),
+        );
+    }
+
+    // Address book recipients, nothing checked sends to the profile's To Email
+    let recipients_row = if state.profiles.address_book.is_empty() {
+        None
+    } else {
+        let mut recipients = row![text("To:")].spacing(12).align_y(Alignment::Center);
+        for recipient in &state.profiles.address_book {
+            let label = if recipient.name.is_empty() {
+                recipient.email.clone()
+            } else {
+                recipient.name.clone()
+            };
+            let email = recipient.email.clone();
+
+            recipients = recipients.push(
+                checkbox(label, state.selected_recipients.contains(&recipient.email))
+                    .on_toggle(move |checked| Message::ToggleRecipient(email.clone(), checked)),
+            );
+        }
+
+        Some(recipients)
+    };
+
+    let merge_toggle = if state.files.len() > 1 {
+        Some(checkbox("Merge into one PDF", state.merge_files).on_toggle(Message::MergeToggled))
+    } else {
+        None
+    };
+
+    let watch_toggle = if state.profiles.watch_folders.is_empty() {
+        None
+    } else {
+        Some(checkbox("Watch folders", state.watching).on_toggle(Message::ToggleWatch))
+    };
+
+    // Per-send override for files already sent to the same address
+    let duplicates_row = if state.duplicate_count() > 0 {
+        Some(
+            checkbox("Send duplicates anyway", state.allow_duplicates)
+                .on_toggle(Message::AllowDuplicatesToggled),
+        )
+    } else {
+        None
+    };
+
+    // Per-send subject, showing the profile's as the placeholder
+    let subject_row = if state.files.is_empty() {
+        None
+    } else {
+        let names: Vec<String> = state.files.iter().map(|f| f.name.clone()).collect();
+        let subject = state
+            .send_profile()
+            .map(|p| email::render_template(&p.settings.subject_template, &names, queue::now()))
+            .unwrap_or_default();
+
+        // Folder the send is tagged with, when there are presets to pick
+        let folder_picker = (!state.profiles.folder_routing.presets.is_empty()).then(|| {
+            row![
+                text("Folder").size(14),
+                pick_list(
+                    FolderChoice::options(state, FolderChoice::NoFolder),
+                    Some(FolderChoice::from_folder(
+                        state.send_folder.as_ref(),
+                        FolderChoice::NoFolder,
+                    )),
+                    |choice| Message::SendFolderSelected(choice.folder()),
+                )
+                .padding(4)
+                .text_size(14),
+            ]
+            .spacing(8)
+            .align_y(Alignment::Center)
+        });
+
+        Some(
+            row![
+                text("Subject").size(14),
+                text_input(&subject, &state.subject_override)
+                    .on_input(Message::SubjectOverrideChanged)
+                    .padding(4)
+                    .size(14),
+            ]
+            .push_maybe(folder_picker)
+            .spacing(8)
+            .align_y(Alignment::Center),
+        )
+    };
+
     let open_btn = button("Open file(s)").on_press(Message::OpenFiles);
-    let can_send =
-        !state.selected_files.is_empty() && state.selected_files.len() <= 5 && !state.sending;
+    let can_send = !state.files.is_empty();
 
-    let send_label = if state.selected_files.len() > 5 {
-        format!("Too many ({} > 5)", state.selected_files.len())
-    } else if state.sending {
-        "Sending...".to_string()
+    let send_label = if state.sending {
+        "Add to queue".to_string()
     } else {
         "Send".to_string()
     };
@@ -281,6 +1541,17 @@ fn main_view(state: &AppState) -> Element<'_, Message> {
         send_btn = send_btn.on_press(Message::Send);
     }
 
+    let profile_picker: Option<Element<'_, Message>> = if state.profiles.profiles.len() > 1 {
+        let selected = state.send_profile().map(|p| p.name.clone());
+        Some(
+            pick_list(state.profiles.names(), selected, Message::SelectSendProfile)
+                .placeholder("Profile")
+                .into(),
+        )
+    } else {
+        None
+    };
+
     let clear_btn = button(text("Clear")).style(|_theme: &iced::Theme, status| {
         let base = Color::from_rgb8(0xD9, 0x2F, 0x2F);
         let hovered = Color::from_rgb8(0xE5, 0x46, 0x46);
@@ -302,14 +1573,14 @@ fn main_view(state: &AppState) -> Element<'_, Message> {
         }
     });
 
-    let clear_btn = if !state.selected_files.is_empty() {
+    let clear_btn = if !state.files.is_empty() {
         clear_btn.on_press(Message::Clear)
     } else {
         clear_btn
     };
 
     let status_row = if let Some(status) = &state.status {
-        let is_error = status.starts_with("Error:") || status.starts_with("Too many");
+        let is_error = status.starts_with("Error:");
 
         if is_error {
             text(status).style(|_theme: &iced::Theme| text::Style {
@@ -326,6 +1597,52 @@ fn main_view(state: &AppState) -> Element<'_, Message> {
         text("")
     };
 
+    // Progress of the email in flight
+    let progress_row = state.send_progress.as_ref().map(|progress| {
+        row![]
+            .push_maybe(progress.fraction().map(|fraction| {
+                progress_bar(0.0..=1.0, fraction)
+                    .height(8)
+                    .width(Length::FillPortion(2))
+            }))
+            .push(
+                text(progress.to_string())
+                    .size(14)
+                    .width(Length::FillPortion(3)),
+            )
+            .spacing(12)
+            .align_y(Alignment::Center)
+    });
+
+    let cancel_btn = if state.sending {
+        Some(
+            button(text("Cancel"))
+                .style(|_theme: &iced::Theme, status| {
+                    let base = Color::from_rgb8(80, 80, 80);
+                    let hovered = Color::from_rgb8(110, 110, 110);
+
+                    let color = match status {
+                        button::Status::Hovered => hovered,
+                        _ => base,
+                    };
+
+                    button::Style {
+                        background: Some(Background::Color(color)),
+                        text_color: Color::WHITE,
+                        border: Border {
+                            radius: 4.0.into(),
+                            width: 0.0,
+                            color: Color::TRANSPARENT,
+                        },
+                        shadow: Shadow::default(),
+                    }
+                })
+                .on_press(Message::CancelSend),
+        )
+    } else {
+        None
+    };
+
     // Settings button for bottom right
     let settings_button = button(text("Settings"))
         .style(|_theme: &iced::Theme, status| {
@@ -351,24 +1668,86 @@ fn main_view(state: &AppState) -> Element<'_, Message> {
         .on_press(Message::OpenSettings)
         .padding(8);
 
-    let content = column![
-        file_list,
-        status_row,
-        row![
-            open_btn,
-            send_btn,
-            clear_btn,
-            container(settings_button)
-                .width(Length::Fill)
-                .align_x(Alignment::End)
-        ]
-        .spacing(16)
-    ]
-    .spacing(12)
-    .padding(16);
+    let pending = state.outbox.pending_count();
+    let queue_label = if pending > 0 {
+        format!("Queue ({})", pending)
+    } else {
+        "Queue".to_string()
+    };
+    let queue_button = button(text(queue_label))
+        .style(|_theme: &iced::Theme, status| {
+            let base = Color::from_rgb8(80, 80, 80);
+            let hovered = Color::from_rgb8(110, 110, 110);
+
+            let color = match status {
+                button::Status::Hovered => hovered,
+                _ => base,
+            };
+
+            button::Style {
+                background: Some(Background::Color(color)),
+                text_color: Color::WHITE,
+                border: Border {
+                    radius: 4.0.into(),
+                    width: 0.0,
+                    color: Color::TRANSPARENT,
+                },
+                shadow: Shadow::default(),
+            }
+        })
+        .on_press(Message::OpenQueue)
+        .padding(8);
+
+    let history_button = button(text("History"))
+        .style(|_theme: &iced::Theme, status| {
+            let base = Color::from_rgb8(80, 80, 80);
+            let hovered = Color::from_rgb8(110, 110, 110);
+
+            let color = match status {
+                button::Status::Hovered => hovered,
+                _ => base,
+            };
+
+            button::Style {
+                background: Some(Background::Color(color)),
+                text_color: Color::WHITE,
+                border: Border {
+                    radius: 4.0.into(),
+                    width: 0.0,
+                    color: Color::TRANSPARENT,
+                },
+                shadow: Shadow::default(),
+            }
+        })
+        .on_press(Message::OpenHistory)
+        .padding(8);
+
+    let content = column![file_list, watch_prompts]
+        .push_maybe(recipients_row)
+        .push_maybe(duplicates_row)
+        .push_maybe(subject_row)
+        .push(status_row)
+        .push_maybe(progress_row)
+        .push(
+            row![open_btn]
+                .push_maybe(profile_picker)
+                .push(send_btn)
+                .push_maybe(cancel_btn)
+                .push(clear_btn)
+                .push_maybe(merge_toggle)
+                .push_maybe(watch_toggle)
+                .push(
+                    container(row![history_button, queue_button, settings_button].spacing(8))
+                        .width(Length::Fill)
+                        .align_x(Alignment::End),
+                )
+                .spacing(16),
+        )
+        .spacing(12)
+        .padding(16);
 
     // Wrap with OutsideCommit so clicks outside will commit edit
-    let editing_active = state.editing_index.is_some();
+    let editing_active = state.editing_file.is_some();
     OutsideCommit::new(
         content.into(),
         editing_active,
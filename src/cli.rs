@@ -0,0 +1,642 @@
+use std::path::PathBuf;
+use std::thread;
+use tokio::runtime::Runtime;
+
+use crate::config::{ConfigManager, FolderTarget, Profiles};
+use crate::email::{self, EmailError};
+use crate::history::History;
+use crate::oauth::{self, OAuthError};
+use crate::pdf;
+use crate::queue;
+use crate::watch;
+
+// Exit codes follow the BSD sysexits(3) conventions so that scripts can tell
+// a bad invocation apart from a misconfigured account or a flaky server.
+const EXIT_OK: i32 = 0;
+const EXIT_USAGE: i32 = 64;
+const EXIT_DATAERR: i32 = 65;
+const EXIT_NOINPUT: i32 = 66;
+const EXIT_UNAVAILABLE: i32 = 69;
+const EXIT_OSERR: i32 = 71;
+const EXIT_TEMPFAIL: i32 = 75;
+const EXIT_CONFIG: i32 = 78;
+
+const PASSPHRASE_VAR: &str = "SEND_TO_GOODNOTES_PASSPHRASE";
+
+const USAGE: &str = "\
+Usage: send-to-goodnotes send <FILE>... [--name <NAME>] [--subject <SUBJECT>] [--folder <FOLDER>] [--profile <PROFILE>] [--to <ADDRESS>]...
+       send-to-goodnotes watch [<FOLDER>...] [--profile <PROFILE>] [--to <ADDRESS>]...
+
+send sends the given PDF files using the settings saved from the app.
+
+watch (or --watch) keeps running and sends every new PDF saved in the given
+folders, or in the watched folders from the settings if none are given.
+
+Options:
+  -n, --name <NAME>        Display name for the file preceding this option (send only)
+  -s, --subject <SUBJECT>  Subject template to use instead of the profile's (send only)
+  -f, --folder <FOLDER>    Destination folder to tag the email or file names with,
+                           one of those saved in the settings (send only)
+  -p, --profile <PROFILE>  Settings profile to send with (default profile if omitted)
+  -t, --to <ADDRESS>       Recipient email or address book name, can be repeated
+                           (the profile's To Email if omitted)
+  -h, --help               Print this help
+
+If the settings are protected by a master passphrase, it is read from the
+SEND_TO_GOODNOTES_PASSPHRASE environment variable.
+
+Running without a subcommand opens the app window.";
+
+/// Runs the command line interface if a subcommand was given.
+///
+/// Returns `None` when the app window should be opened instead, otherwise
+/// the process exit code.
+pub fn run(args: Vec<String>) -> Option<i32> {
+    let (command, rest) = args.split_first()?;
+
+    let code = match command.as_str() {
+        "send" => match parse_send_args(rest) {
+            Ok(Some(args)) => send(args),
+            Ok(None) => {
+                println!("{USAGE}");
+                EXIT_OK
+            }
+            Err(e) => {
+                eprintln!("error: {e}\n\n{USAGE}");
+                EXIT_USAGE
+            }
+        },
+        "watch" | "--watch" => match parse_watch_args(rest) {
+            Ok(Some(args)) => watch(args),
+            Ok(None) => {
+                println!("{USAGE}");
+                EXIT_OK
+            }
+            Err(e) => {
+                eprintln!("error: {e}\n\n{USAGE}");
+                EXIT_USAGE
+            }
+        },
+        "-h" | "--help" | "help" => {
+            println!("{USAGE}");
+            EXIT_OK
+        }
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+struct SendArgs {
+    files: Vec<(PathBuf, String)>,
+    subject: Option<String>,
+    folder: Option<String>,
+    profile: Option<String>,
+    recipients: Vec<String>,
+}
+
+/// Parses the arguments of the `send` subcommand. Returns `Ok(None)` if help
+/// was requested.
+fn parse_send_args(args: &[String]) -> Result<Option<SendArgs>, String> {
+    let mut files: Vec<(PathBuf, String)> = Vec::new();
+    let mut subject = None;
+    let mut folder = None;
+    let mut profile = None;
+    let mut recipients = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-s" | "--subject" => {
+                let template = iter
+                    .next()
+                    .ok_or_else(|| format!("{arg} requires a value"))?;
+                subject = Some(template.clone());
+            }
+            "-f" | "--folder" => {
+                let name = iter
+                    .next()
+                    .ok_or_else(|| format!("{arg} requires a value"))?;
+                folder = Some(name.clone());
+            }
+            "-n" | "--name" => {
+                let name = iter
+                    .next()
+                    .ok_or_else(|| format!("{arg} requires a value"))?;
+                let (_, display_name) = files
+                    .last_mut()
+                    .ok_or_else(|| format!("{arg} must follow a file"))?;
+                *display_name = name.clone();
+            }
+            "-p" | "--profile" => {
+                let name = iter
+                    .next()
+                    .ok_or_else(|| format!("{arg} requires a value"))?;
+                profile = Some(name.clone());
+            }
+            "-t" | "--to" => {
+                let recipient = iter
+                    .next()
+                    .ok_or_else(|| format!("{arg} requires a value"))?;
+                recipients.push(recipient.clone());
+            }
+            a if a.starts_with('-') && a.len() > 1 => {
+                return Err(format!("unknown option: {a}"));
+            }
+            path => {
+                let path = PathBuf::from(path);
+                if !crate::is_pdf(&path) {
+                    return Err(format!("not a PDF file: {}", path.display()));
+                }
+
+                let name = crate::default_display_name(&path);
+                files.push((path, name));
+            }
+        }
+    }
+
+    if files.is_empty() {
+        return Err("no files given".to_string());
+    }
+
+    Ok(Some(SendArgs {
+        files,
+        subject,
+        folder,
+        profile,
+        recipients,
+    }))
+}
+
+struct WatchArgs {
+    folders: Vec<PathBuf>,
+    profile: Option<String>,
+    recipients: Vec<String>,
+}
+
+/// Parses the arguments of the `watch` subcommand. Returns `Ok(None)` if
+/// help was requested.
+fn parse_watch_args(args: &[String]) -> Result<Option<WatchArgs>, String> {
+    let mut folders = Vec::new();
+    let mut profile = None;
+    let mut recipients = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-p" | "--profile" => {
+                let name = iter
+                    .next()
+                    .ok_or_else(|| format!("{arg} requires a value"))?;
+                profile = Some(name.clone());
+            }
+            "-t" | "--to" => {
+                let recipient = iter
+                    .next()
+                    .ok_or_else(|| format!("{arg} requires a value"))?;
+                recipients.push(recipient.clone());
+            }
+            a if a.starts_with('-') && a.len() > 1 => {
+                return Err(format!("unknown option: {a}"));
+            }
+            path => {
+                let path = PathBuf::from(path);
+                if !path.is_dir() {
+                    return Err(format!("not a folder: {}", path.display()));
+                }
+
+                folders.push(path);
+            }
+        }
+    }
+
+    Ok(Some(WatchArgs {
+        folders,
+        profile,
+        recipients,
+    }))
+}
+
+fn send(args: SendArgs) -> i32 {
+    let mut session = match Session::open(args.profile, &args.recipients) {
+        Ok(session) => session,
+        Err(code) => return code,
+    };
+
+    if let Some(folder) = &args.folder
+        && !session.profiles.folder_routing.presets.contains(folder)
+    {
+        eprintln!("error: unknown folder: {folder}");
+        return EXIT_USAGE;
+    }
+
+    session.send(args.files, args.subject.as_deref(), args.folder.as_deref())
+}
+
+fn watch(args: WatchArgs) -> i32 {
+    let mut session = match Session::open(args.profile, &args.recipients) {
+        Ok(session) => session,
+        Err(code) => return code,
+    };
+
+    let folders: Vec<PathBuf> = if args.folders.is_empty() {
+        session
+            .profiles
+            .watch_folders
+            .iter()
+            .map(PathBuf::from)
+            .collect()
+    } else {
+        args.folders
+    };
+    if folders.is_empty() {
+        eprintln!("error: no folders given and none configured in the settings");
+        return EXIT_USAGE;
+    }
+
+    // Files are sent on the watcher's thread. Failures are reported and
+    // the file is tried again later, and watching goes on until interrupted
+    let _watcher = match watch::watch(&folders, move |path| {
+        println!("Found {}", path.display());
+        let name = crate::default_display_name(&path);
+        session.send(vec![(path, name)], None, None) == EXIT_OK
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("error: could not watch folders: {e}");
+            return EXIT_NOINPUT;
+        }
+    };
+
+    for folder in &folders {
+        println!("Watching {}", folder.display());
+    }
+
+    loop {
+        thread::park();
+    }
+}
+
+/// Settings, history and async runtime shared by the sends of one run.
+struct Session {
+    profiles: Profiles,
+    profile: Option<String>,
+    recipients: Vec<String>,
+    history: History,
+    manager: ConfigManager,
+    runtime: Runtime,
+}
+
+impl Session {
+    /// Loads the settings and checks that `profile` exists. Fails with the
+    /// exit code to return.
+    fn open(profile: Option<String>, recipients: &[String]) -> Result<Self, i32> {
+        let (manager, profiles) = match ConfigManager::new().and_then(|mut m| {
+            m.set_passphrase(std::env::var(PASSPHRASE_VAR).ok());
+            let profiles = m.load_settings()?;
+            Ok((m, profiles))
+        }) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                eprintln!("error: could not load settings: {e}");
+                return Err(EXIT_CONFIG);
+            }
+        };
+
+        let exists = match &profile {
+            Some(name) => profiles.get(name).is_some(),
+            None => profiles.default_profile().is_some(),
+        };
+        if !exists {
+            eprintln!(
+                "error: unknown profile: {}",
+                profile.as_deref().unwrap_or_default()
+            );
+            return Err(EXIT_CONFIG);
+        }
+
+        let recipients = recipients
+            .iter()
+            .map(|r| profiles.resolve_recipient(r))
+            .collect();
+
+        let history = History::load(manager.history_path()).unwrap_or_else(|e| {
+            eprintln!("warning: could not load history, sends won't be logged: {e}");
+            History::default()
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| {
+                eprintln!("error: could not start the async runtime: {e}");
+                EXIT_OSERR
+            })?;
+
+        Ok(Self {
+            profiles,
+            profile,
+            recipients,
+            history,
+            manager,
+            runtime,
+        })
+    }
+
+    /// Sends `files` in as many emails as needed, returning the exit code.
+    ///
+    /// `subject` replaces the profile's subject template when given, and
+    /// `folder` is added to the subject or file names as configured.
+    fn send(
+        &mut self,
+        mut files: Vec<(PathBuf, String)>,
+        subject: Option<&str>,
+        folder: Option<&str>,
+    ) -> i32 {
+        let profile = match &self.profile {
+            Some(name) => self.profiles.get(name),
+            None => self.profiles.default_profile(),
+        };
+        let Some(settings) = profile.map(|p| &p.settings) else {
+            return EXIT_CONFIG;
+        };
+
+        let routing = &self.profiles.folder_routing;
+        let folder = match (folder, routing.target) {
+            (Some(folder), FolderTarget::Subject) => Some((routing, folder)),
+            (Some(folder), FolderTarget::FileName) => {
+                for (_, name) in &mut files {
+                    *name = routing.apply_to_name(folder, name);
+                }
+                None
+            }
+            (None, _) => None,
+        };
+
+        let batches = match email::split_into_batches(files, settings) {
+            Ok(batches) => batches,
+            Err(e) => {
+                eprintln!("error: {e}");
+                return exit_code(&e);
+            }
+        };
+
+        for (path, _) in batches.iter().flatten() {
+            let validation = pdf::validate(path);
+            if !validation.is_valid() {
+                eprintln!("error: {}: {validation}", path.display());
+                return EXIT_DATAERR;
+            }
+        }
+
+        let groups = email::recipient_groups(settings, &self.recipients);
+
+        let emails: Vec<_> = batches
+            .iter()
+            .flat_map(|batch| groups.iter().map(move |group| (batch, group)))
+            .collect();
+        let email_count = emails.len();
+        let mut sent = 0;
+
+        for (i, (batch, group)) in emails.into_iter().enumerate() {
+            match self.runtime.block_on(email::send_pdfs(
+                batch.clone(),
+                settings,
+                group,
+                subject,
+                folder,
+                |_| {},
+            )) {
+                Ok(sent_email) => {
+                    sent += sent_email.files.len();
+                    self.history
+                        .record(sent_email, self.profile.clone(), queue::now());
+                    if let Err(e) = self.history.save() {
+                        eprintln!("warning: could not save history: {e}");
+                    }
+
+                    if email_count > 1 {
+                        println!(
+                            "Sent email {} of {email_count} to {}",
+                            i + 1,
+                            group.join(", ")
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("error: {e}");
+                    if i > 0 {
+                        eprintln!("{i} of {email_count} email(s) were already sent");
+                    }
+                    self.save_rotated_refresh_tokens();
+                    return exit_code(&e);
+                }
+            }
+        }
+
+        self.save_rotated_refresh_tokens();
+        println!("Sent {sent} attachment(s)");
+        EXIT_OK
+    }
+
+    /// Saves refresh tokens the OAuth2 provider rotated while sending, as
+    /// the old ones may no longer work.
+    fn save_rotated_refresh_tokens(&self) {
+        let rotated = oauth::take_rotated_refresh_tokens();
+        if !rotated.is_empty()
+            && let Err(e) = self.manager.save_refresh_tokens(&rotated)
+        {
+            eprintln!("warning: could not save the renewed OAuth2 sign-in: {e}");
+        }
+    }
+}
+
+fn exit_code(err: &EmailError) -> i32 {
+    match err {
+        EmailError::TooManyAttachments(_)
+        | EmailError::Build(_)
+        | EmailError::AttachmentTooLarge { .. }
+        | EmailError::MessageTooLarge { .. } => EXIT_DATAERR,
+        EmailError::Io(_) => EXIT_NOINPUT,
+        EmailError::Smtp(e) if e.is_transient() => EXIT_TEMPFAIL,
+        EmailError::ConnectTimeout { .. } => EXIT_TEMPFAIL,
+        EmailError::Smtp(_)
+        | EmailError::DnsLookup(_)
+        | EmailError::PortClosed { .. }
+        | EmailError::TlsHandshake(_) => EXIT_UNAVAILABLE,
+        EmailError::Addr(_)
+        | EmailError::InvalidPort(_)
+        | EmailError::IncompleteSettings
+        | EmailError::InvalidSizeLimit(_)
+        | EmailError::AuthRejected(_)
+        | EmailError::Certificate { .. } => EXIT_CONFIG,
+        EmailError::OAuth(OAuthError::Http(_) | OAuthError::Io(_)) => EXIT_TEMPFAIL,
+        EmailError::OAuth(_) => EXIT_CONFIG,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    /// Error of an SMTP server that greets with `greeting`.
+    fn smtp_error(greeting: &'static str) -> EmailError {
+        use lettre::{AsyncSmtpTransport, Tokio1Executor};
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(greeting.as_bytes()).unwrap();
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+                    .port(port)
+                    .build::<Tokio1Executor>()
+                    .test_connection()
+                    .await
+            })
+            .unwrap_err()
+            .into()
+    }
+
+    #[test]
+    fn send_args_name_the_file_before_them() {
+        let parsed = parse_send_args(&args(&[
+            "a.pdf",
+            "--name",
+            "First",
+            "b.pdf",
+            "-s",
+            "{date}",
+            "-f",
+            "Work",
+            "-p",
+            "home",
+            "-t",
+            "x@example.com",
+            "--to",
+            "Alice",
+        ]))
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            parsed.files,
+            [
+                (PathBuf::from("a.pdf"), "First".to_string()),
+                (PathBuf::from("b.pdf"), "b.pdf".to_string()),
+            ]
+        );
+        assert_eq!(parsed.subject.as_deref(), Some("{date}"));
+        assert_eq!(parsed.folder.as_deref(), Some("Work"));
+        assert_eq!(parsed.profile.as_deref(), Some("home"));
+        assert_eq!(parsed.recipients, ["x@example.com", "Alice"]);
+    }
+
+    #[test]
+    fn invalid_send_args_are_rejected() {
+        let error = |a: &[&str]| parse_send_args(&args(a)).err().unwrap();
+
+        assert_eq!(
+            error(&["--name", "First", "a.pdf"]),
+            "--name must follow a file"
+        );
+        assert_eq!(error(&["a.pdf", "--bogus"]), "unknown option: --bogus");
+        assert_eq!(error(&["a.pdf", "--subject"]), "--subject requires a value");
+        assert_eq!(error(&["notes.txt"]), "not a PDF file: notes.txt");
+        assert_eq!(error(&["-p", "home"]), "no files given");
+
+        assert!(parse_send_args(&args(&["a.pdf", "-h"])).unwrap().is_none());
+    }
+
+    #[test]
+    fn watch_args_take_folders_and_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().to_str().unwrap();
+
+        let parsed = parse_watch_args(&args(&[folder, "--profile", "work", "-t", "Bob"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.folders, [dir.path()]);
+        assert_eq!(parsed.profile.as_deref(), Some("work"));
+        assert_eq!(parsed.recipients, ["Bob"]);
+
+        // Without folders the configured ones are used
+        assert!(parse_watch_args(&[]).unwrap().unwrap().folders.is_empty());
+    }
+
+    #[test]
+    fn invalid_watch_args_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let missing = missing.to_str().unwrap();
+        let error = |a: &[&str]| parse_watch_args(&args(a)).err().unwrap();
+
+        assert_eq!(error(&["-x"]), "unknown option: -x");
+        assert_eq!(error(&["--to"]), "--to requires a value");
+        assert_eq!(error(&[missing]), format!("not a folder: {missing}"));
+
+        assert!(parse_watch_args(&args(&["--help"])).unwrap().is_none());
+    }
+
+    #[test]
+    fn errors_map_to_sysexits_codes() {
+        let host = || "smtp.example.com".to_string();
+
+        assert_eq!(exit_code(&EmailError::TooManyAttachments(11)), EXIT_DATAERR);
+        assert_eq!(
+            exit_code(&EmailError::MessageTooLarge { size: 2, limit: 1 }),
+            EXIT_DATAERR
+        );
+        assert_eq!(
+            exit_code(&std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+            EXIT_NOINPUT
+        );
+        assert_eq!(
+            exit_code(&EmailError::ConnectTimeout {
+                host: host(),
+                port: 587
+            }),
+            EXIT_TEMPFAIL
+        );
+        assert_eq!(
+            exit_code(&OAuthError::Http("offline".to_string()).into()),
+            EXIT_TEMPFAIL
+        );
+        assert_eq!(
+            exit_code(&EmailError::PortClosed {
+                host: host(),
+                port: 587
+            }),
+            EXIT_UNAVAILABLE
+        );
+        assert_eq!(
+            exit_code(&EmailError::DnsLookup("no such host".to_string())),
+            EXIT_UNAVAILABLE
+        );
+        assert_eq!(exit_code(&EmailError::IncompleteSettings), EXIT_CONFIG);
+        assert_eq!(
+            exit_code(&EmailError::AuthRejected("535".to_string())),
+            EXIT_CONFIG
+        );
+        assert_eq!(exit_code(&OAuthError::NotSignedIn.into()), EXIT_CONFIG);
+
+        // SMTP replies depend on whether retrying may help
+        assert_eq!(exit_code(&smtp_error("421 busy\r\n")), EXIT_TEMPFAIL);
+        assert_eq!(exit_code(&smtp_error("554 go away\r\n")), EXIT_UNAVAILABLE);
+    }
+}
@@ -2,10 +2,13 @@ use aes_gcm::{
     Aes256Gcm, Nonce,
     aead::{Aead, KeyInit, OsRng, rand_core::RngCore},
 };
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{Engine as _, engine::general_purpose};
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -20,15 +23,378 @@ pub enum ConfigError {
     Decryption(String),
     #[error("Config directory not found")]
     ConfigDirNotFound,
+    #[error("Invalid profiles: {0}")]
+    InvalidProfiles(String),
+    #[error("Settings are protected by a master passphrase")]
+    PassphraseRequired,
+    #[error("Settings were saved by a newer version of the app (format version {0})")]
+    UnsupportedVersion(u32),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct EmailSettings {
     pub smtp_host: String,
     pub smtp_port: String,
     pub from_email: String,
     pub to_email: String,
     pub app_password: String,
+    pub max_message_size_mb: String,
+    pub recipient_mode: RecipientMode,
+    pub auth_method: AuthMethod,
+    pub oauth: OAuthSettings,
+    pub tls_mode: TlsMode,
+    /// PEM file with an extra CA certificate to trust, empty for none.
+    pub ca_cert_path: String,
+    /// PEM file with the server's own (e.g. self-signed) certificate. When
+    /// set, it is the only certificate trusted and the host name isn't checked.
+    pub pinned_cert_path: String,
+    /// Subject of sent emails, see [`crate::email::render_template`] for
+    /// the placeholders.
+    pub subject_template: String,
+    /// Text sent along with the attachments, empty for none.
+    pub body_template: String,
+}
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TlsMode {
+    /// TLS from the start of the connection, usually port 465.
+    Implicit,
+    /// Plain connection upgraded with STARTTLS, failing if unsupported.
+    #[default]
+    StartTlsRequired,
+    /// STARTTLS if the server offers it, plaintext otherwise.
+    StartTlsOpportunistic,
+    /// No encryption, for local relays only.
+    Plaintext,
+}
+
+impl TlsMode {
+    pub const ALL: [TlsMode; 4] = [
+        TlsMode::Implicit,
+        TlsMode::StartTlsRequired,
+        TlsMode::StartTlsOpportunistic,
+        TlsMode::Plaintext,
+    ];
+}
+
+impl std::fmt::Display for TlsMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TlsMode::Implicit => "Implicit TLS (SSL)",
+            TlsMode::StartTlsRequired => "STARTTLS (required)",
+            TlsMode::StartTlsOpportunistic => "STARTTLS (if available)",
+            TlsMode::Plaintext => "None (plaintext)",
+        })
+    }
+}
+
+/// How the app logs in to the SMTP server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthMethod {
+    /// Plain login with the app password.
+    #[default]
+    Password,
+    /// XOAUTH2 with an access token obtained through OAuth2.
+    OAuth2,
+}
+
+impl AuthMethod {
+    pub const ALL: [AuthMethod; 2] = [AuthMethod::Password, AuthMethod::OAuth2];
+}
+
+impl std::fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AuthMethod::Password => "App password",
+            AuthMethod::OAuth2 => "OAuth2 (sign in with browser)",
+        })
+    }
+}
+
+/// OAuth2 providers with known endpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OAuthProvider {
+    #[default]
+    Google,
+    Microsoft,
+    Custom,
+}
+
+impl OAuthProvider {
+    pub const ALL: [OAuthProvider; 3] = [
+        OAuthProvider::Google,
+        OAuthProvider::Microsoft,
+        OAuthProvider::Custom,
+    ];
+
+    /// Authorization URL, token URL and scope of the provider.
+    pub fn endpoints(&self) -> Option<(&'static str, &'static str, &'static str)> {
+        match self {
+            OAuthProvider::Google => Some((
+                "https://accounts.google.com/o/oauth2/v2/auth",
+                "https://oauth2.googleapis.com/token",
+                "https://mail.google.com/",
+            )),
+            OAuthProvider::Microsoft => Some((
+                "https://login.microsoftonline.com/common/oauth2/v2.0/authorize",
+                "https://login.microsoftonline.com/common/oauth2/v2.0/token",
+                "https://outlook.office.com/SMTP.Send offline_access",
+            )),
+            OAuthProvider::Custom => None,
+        }
+    }
+}
+
+impl std::fmt::Display for OAuthProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OAuthProvider::Google => "Google (Gmail)",
+            OAuthProvider::Microsoft => "Microsoft (Outlook)",
+            OAuthProvider::Custom => "Custom",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OAuthSettings {
+    pub provider: OAuthProvider,
+    pub client_id: String,
+    pub client_secret: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub scope: String,
+    /// Set by signing in, used to obtain access tokens before sending.
+    pub refresh_token: String,
+}
+
+impl OAuthSettings {
+    /// Switches to `provider`, filling in its endpoints.
+    pub fn set_provider(&mut self, provider: OAuthProvider) {
+        self.provider = provider;
+        if let Some((auth_url, token_url, scope)) = provider.endpoints() {
+            self.auth_url = auth_url.to_string();
+            self.token_url = token_url.to_string();
+            self.scope = scope.to_string();
+        }
+    }
+}
+
+impl Default for OAuthSettings {
+    fn default() -> Self {
+        let mut settings = Self {
+            provider: OAuthProvider::default(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_url: String::new(),
+            token_url: String::new(),
+            scope: String::new(),
+            refresh_token: String::new(),
+        };
+        settings.set_provider(OAuthProvider::default());
+
+        settings
+    }
+}
+
+/// How a send to several recipients is delivered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecipientMode {
+    /// A separate email for every recipient.
+    #[default]
+    Separate,
+    /// A single email listing all recipients.
+    Combined,
+}
+
+impl RecipientMode {
+    pub const ALL: [RecipientMode; 2] = [RecipientMode::Separate, RecipientMode::Combined];
+}
+
+impl std::fmt::Display for RecipientMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RecipientMode::Separate => "One email per recipient",
+            RecipientMode::Combined => "One email to all recipients",
+        })
+    }
+}
+
+/// Page size of the PDFs made from images.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PageSize {
+    #[default]
+    A4,
+    Letter,
+    /// Each page is the size of its image.
+    Image,
+}
+
+impl PageSize {
+    pub const ALL: [PageSize; 3] = [PageSize::A4, PageSize::Letter, PageSize::Image];
+
+    /// Width and height of a portrait page in points, `None` for
+    /// [`PageSize::Image`].
+    pub fn dimensions(&self) -> Option<(f32, f32)> {
+        match self {
+            PageSize::A4 => Some((595.28, 841.89)),
+            PageSize::Letter => Some((612.0, 792.0)),
+            PageSize::Image => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PageSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PageSize::A4 => "A4",
+            PageSize::Letter => "US Letter",
+            PageSize::Image => "Same as the image",
+        })
+    }
+}
+
+/// How an image is scaled onto its page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageFit {
+    /// The whole image is visible, centered with blank borders.
+    #[default]
+    Fit,
+    /// The image covers the page, cropping its edges.
+    Fill,
+}
+
+impl ImageFit {
+    pub const ALL: [ImageFit; 2] = [ImageFit::Fit, ImageFit::Fill];
+}
+
+impl std::fmt::Display for ImageFit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ImageFit::Fit => "Fit the whole image",
+            ImageFit::Fill => "Fill the page (crop)",
+        })
+    }
+}
+
+/// Optimization of PDFs too large to fit in an email.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionSettings {
+    pub enabled: bool,
+    /// Resolution images are downsampled to.
+    pub dpi: String,
+    /// JPEG quality (1-100) images are re-encoded with.
+    pub quality: String,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dpi: "150".to_string(),
+            quality: "75".to_string(),
+        }
+    }
+}
+
+impl CompressionSettings {
+    /// Parsed DPI and JPEG quality.
+    pub fn parse(&self) -> Result<(u32, u8), String> {
+        let dpi = self
+            .dpi
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|dpi| *dpi > 0)
+            .ok_or_else(|| format!("invalid image resolution: {}", self.dpi))?;
+        let quality = self
+            .quality
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|q| (1..=100).contains(q))
+            .ok_or_else(|| format!("invalid JPEG quality: {}", self.quality))?;
+
+        Ok((dpi, quality))
+    }
+}
+
+/// Where the destination folder of a send is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FolderTarget {
+    /// In the subject, so that files of different folders go in separate
+    /// emails.
+    #[default]
+    Subject,
+    /// In the name of every attachment.
+    FileName,
+}
+
+impl FolderTarget {
+    pub const ALL: [FolderTarget; 2] = [FolderTarget::Subject, FolderTarget::FileName];
+}
+
+impl std::fmt::Display for FolderTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FolderTarget::Subject => "Email subject",
+            FolderTarget::FileName => "File names",
+        })
+    }
+}
+
+/// Destination folders that sends can be tagged with, for GoodNotes to sort
+/// them by.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FolderRouting {
+    /// Saved folder names to pick from.
+    pub presets: Vec<String>,
+    pub target: FolderTarget,
+    /// How the folder is combined with the subject or file name, with
+    /// `{folder}` and `{text}` placeholders.
+    pub pattern: String,
+}
+
+impl Default for FolderRouting {
+    fn default() -> Self {
+        Self {
+            presets: Vec::new(),
+            target: FolderTarget::default(),
+            pattern: "[{folder}] {text}".to_string(),
+        }
+    }
+}
+
+impl FolderRouting {
+    /// `text` tagged with `folder` according to the pattern.
+    pub fn apply(&self, folder: &str, text: &str) -> String {
+        // Split on `{folder}` first so that a folder named `{text}` stays as is
+        self.pattern
+            .split("{folder}")
+            .map(|part| part.replace("{text}", text))
+            .collect::<Vec<_>>()
+            .join(folder)
+    }
+
+    /// Display name `name` tagged with `folder`, keeping the `.pdf`
+    /// extension at the end.
+    pub fn apply_to_name(&self, folder: &str, name: &str) -> String {
+        format!(
+            "{}.pdf",
+            self.apply(folder, &crate::pdf::strip_pdf_extension(name))
+        )
+    }
+}
+
+/// A named GoodNotes inbox address.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Recipient {
+    pub name: String,
+    pub email: String,
 }
 
 impl Default for EmailSettings {
@@ -39,20 +405,244 @@ impl Default for EmailSettings {
             from_email: String::new(),
             to_email: String::new(),
             app_password: String::new(),
+            max_message_size_mb: "25".to_string(),
+            recipient_mode: RecipientMode::default(),
+            auth_method: AuthMethod::default(),
+            oauth: OAuthSettings::default(),
+            tls_mode: TlsMode::default(),
+            ca_cert_path: String::new(),
+            pinned_cert_path: String::new(),
+            subject_template: "PDF files".to_string(),
+            body_template: String::new(),
+        }
+    }
+}
+
+/// A named set of email settings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub name: String,
+    pub settings: EmailSettings,
+}
+
+/// All saved profiles, along with the one used unless another is picked,
+/// and the address book and watched folders shared between them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profiles {
+    pub profiles: Vec<Profile>,
+    pub default: String,
+    pub address_book: Vec<Recipient>,
+    /// Folders whose new PDFs are sent automatically in watch mode.
+    pub watch_folders: Vec<String>,
+    /// Ask before sending a file found in a watched folder.
+    pub watch_confirm: bool,
+    pub image_page_size: PageSize,
+    pub image_fit: ImageFit,
+    pub compression: CompressionSettings,
+    pub folder_routing: FolderRouting,
+}
+
+pub const DEFAULT_PROFILE_NAME: &str = "Default";
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self::from_settings(EmailSettings::default())
+    }
+}
+
+impl Profiles {
+    fn from_settings(settings: EmailSettings) -> Self {
+        Self {
+            profiles: vec![Profile {
+                name: DEFAULT_PROFILE_NAME.to_string(),
+                settings,
+            }],
+            default: DEFAULT_PROFILE_NAME.to_string(),
+            address_book: Vec::new(),
+            watch_folders: Vec::new(),
+            watch_confirm: false,
+            image_page_size: PageSize::default(),
+            image_fit: ImageFit::default(),
+            compression: CompressionSettings::default(),
+            folder_routing: FolderRouting::default(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// The default profile, or the first one if the default was removed.
+    pub fn default_profile(&self) -> Option<&Profile> {
+        self.get(&self.default).or_else(|| self.profiles.first())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.profiles.iter().map(|p| p.name.clone()).collect()
+    }
+
+    /// Resolves an address book entry by name, or returns `name_or_email`
+    /// unchanged if no entry matches.
+    pub fn resolve_recipient(&self, name_or_email: &str) -> String {
+        self.address_book
+            .iter()
+            .find(|r| r.name == name_or_email)
+            .map(|r| r.email.clone())
+            .unwrap_or_else(|| name_or_email.to_string())
+    }
+
+    /// Returns a name based on `base` that no existing profile uses.
+    pub fn unique_name(&self, base: &str) -> String {
+        let mut name = base.to_string();
+        let mut n = 2;
+        while self.get(&name).is_some() {
+            name = format!("{base} {n}");
+            n += 1;
         }
+
+        name
+    }
+
+    /// Swaps each `(old, new)` OAuth2 refresh token in `rotated` into the
+    /// profiles signed in with `old`. Returns whether any profile changed.
+    pub fn replace_refresh_tokens(&mut self, rotated: &[(String, String)]) -> bool {
+        let mut changed = false;
+        for oauth in self.profiles.iter_mut().map(|p| &mut p.settings.oauth) {
+            if let Some((_, new)) = rotated.iter().find(|(old, _)| *old == oauth.refresh_token) {
+                oauth.refresh_token = new.clone();
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Restores the default profile if the list of profiles is empty, which
+    /// hand-edited or damaged settings may have.
+    fn repair(&mut self) {
+        if self.profiles.is_empty() {
+            self.profiles = Self::default().profiles;
+            self.default = DEFAULT_PROFILE_NAME.to_string();
+        }
+    }
+
+    /// Checks that there is at least one profile and names are non-empty and unique.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.profiles.is_empty() {
+            return Err(ConfigError::InvalidProfiles(
+                "at least one profile is required".to_string(),
+            ));
+        }
+
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if profile.name.trim().is_empty() {
+                return Err(ConfigError::InvalidProfiles(
+                    "profile names cannot be empty".to_string(),
+                ));
+            }
+
+            if self.profiles[..i].iter().any(|p| p.name == profile.name) {
+                return Err(ConfigError::InvalidProfiles(format!(
+                    "duplicate profile name: {}",
+                    profile.name
+                )));
+            }
+        }
+
+        Ok(())
     }
 }
 
+/// Where the key that encrypts `settings.json` comes from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum KeySource {
+    /// Derived from the username. Only read, to migrate old installs.
+    #[default]
+    Legacy,
+    /// The random key stored in `secret.key`.
+    KeyFile,
+    /// Argon2id over the master passphrase, using `secret.key` as the secret.
+    Passphrase { salt: String },
+}
+
 #[derive(Serialize, Deserialize)]
 struct EncryptedConfig {
+    /// Format version of the encrypted settings, missing before versioning.
+    #[serde(default)]
+    version: u32,
     data: String,
     nonce: String,
+    #[serde(default)]
+    key: KeySource,
+}
+
+/// Format version of the settings written by this build.
+const CONFIG_VERSION: u32 = 2;
+
+/// Upgrades the decrypted settings one format version at a time.
+/// `MIGRATIONS[n]` turns version `n` into version `n + 1`.
+const MIGRATIONS: [fn(Value) -> Value; CONFIG_VERSION as usize] =
+    [migrate_v0_to_v1, migrate_v1_to_v2];
+
+/// Unversioned settings held either a single set of email settings or, in
+/// later builds, the list of profiles.
+fn migrate_v0_to_v1(value: Value) -> Value {
+    if value.get("profiles").is_some() {
+        return value;
+    }
+
+    json!({
+        "profiles": [{ "name": DEFAULT_PROFILE_NAME, "settings": value }],
+        "default": DEFAULT_PROFILE_NAME,
+    })
+}
+
+/// The TLS mode used to be picked from the port: implicit TLS on 465 and
+/// STARTTLS on anything else.
+fn migrate_v1_to_v2(mut value: Value) -> Value {
+    if let Some(profiles) = value.get_mut("profiles").and_then(Value::as_array_mut) {
+        for settings in profiles.iter_mut().filter_map(|p| p.get_mut("settings")) {
+            let implicit = settings
+                .get("smtp_port")
+                .and_then(Value::as_str)
+                .is_some_and(|port| port.trim() == "465");
+
+            if let Some(settings) = settings.as_object_mut() {
+                settings.entry("tls_mode").or_insert_with(|| {
+                    json!(if implicit {
+                        "Implicit"
+                    } else {
+                        "StartTlsRequired"
+                    })
+                });
+            }
+        }
+    }
+
+    value
+}
+
+fn migrate(mut value: Value, version: u32) -> Result<Value, ConfigError> {
+    if version > CONFIG_VERSION {
+        return Err(ConfigError::UnsupportedVersion(version));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        value = migration(value);
+    }
+
+    Ok(value)
 }
 
 #[derive(Clone)]
 pub struct ConfigManager {
+    config_dir: PathBuf,
     config_path: PathBuf,
     key: [u8; 32],
+    passphrase: Option<String>,
 }
 
 impl ConfigManager {
@@ -61,40 +651,74 @@ impl ConfigManager {
             .ok_or(ConfigError::ConfigDirNotFound)?
             .join("send-to-goodnotes");
 
+        Self::in_dir(config_dir)
+    }
+
+    /// Keeps the settings, key and queue in `config_dir` instead of the
+    /// user's config directory.
+    fn in_dir(config_dir: PathBuf) -> Result<Self, ConfigError> {
         fs::create_dir_all(&config_dir)?;
 
         let config_path = config_dir.join("settings.json");
+        let key = load_or_create_key(&config_dir.join("secret.key"))?;
 
-        // Generate a simple key based on machine characteristics
-        // In a real app, you might want to use a more sophisticated key derivation
-        let machine_id = whoami::username();
-        let mut key = [0u8; 32];
-        let machine_bytes = machine_id.as_bytes();
-        for (i, &byte) in machine_bytes.iter().enumerate() {
-            if i >= 32 {
-                break;
-            }
-            key[i] = byte;
-        }
+        Ok(Self {
+            config_dir,
+            config_path,
+            key,
+            passphrase: None,
+        })
+    }
 
-        // Fill remaining bytes with a pattern if machine_id is short
-        for (i, slot) in key.iter_mut().enumerate().skip(machine_bytes.len()) {
-            *slot = (i as u8).wrapping_mul(7).wrapping_add(42);
-        }
+    /// Sets the master passphrase used to load and save settings. `None`
+    /// saves with the key file alone.
+    pub fn set_passphrase(&mut self, passphrase: Option<String>) {
+        self.passphrase = passphrase.filter(|p| !p.is_empty());
+    }
+
+    /// Location of the persistent send queue, next to `settings.json`.
+    pub fn outbox_path(&self) -> PathBuf {
+        self.config_dir.join("outbox.json")
+    }
 
-        Ok(Self { config_path, key })
+    /// Location of the log of sent emails, next to `settings.json`.
+    pub fn history_path(&self) -> PathBuf {
+        self.config_dir.join("history.json")
     }
 
-    pub fn load_settings(&self) -> Result<EmailSettings, ConfigError> {
+    /// Loads the saved profiles.
+    ///
+    /// Fails with [`ConfigError::PassphraseRequired`] if the settings are
+    /// protected by a master passphrase that hasn't been set. Settings in an
+    /// older format, or saved with the old username-derived key, are migrated
+    /// and saved again.
+    pub fn load_settings(&self) -> Result<Profiles, ConfigError> {
         if !self.config_path.exists() {
-            return Ok(EmailSettings::default());
+            return Ok(Profiles::default());
         }
 
         let encrypted_data = fs::read_to_string(&self.config_path)?;
         let encrypted_config: EncryptedConfig = serde_json::from_str(&encrypted_data)?;
 
-        let cipher = Aes256Gcm::new_from_slice(&self.key)
-            .map_err(|e| ConfigError::Decryption(e.to_string()))?;
+        let key = match &encrypted_config.key {
+            KeySource::Legacy => legacy_key(),
+            KeySource::KeyFile => self.key,
+            KeySource::Passphrase { salt } => {
+                let passphrase = self
+                    .passphrase
+                    .as_deref()
+                    .ok_or(ConfigError::PassphraseRequired)?;
+                let salt = general_purpose::STANDARD
+                    .decode(salt)
+                    .map_err(|e| ConfigError::Decryption(e.to_string()))?;
+
+                self.derive_key(passphrase, &salt)
+                    .map_err(|e| ConfigError::Decryption(e.to_string()))?
+            }
+        };
+
+        let cipher =
+            Aes256Gcm::new_from_slice(&key).map_err(|e| ConfigError::Decryption(e.to_string()))?;
 
         let nonce_bytes = general_purpose::STANDARD
             .decode(&encrypted_config.nonce)
@@ -110,13 +734,46 @@ impl ConfigManager {
             .decrypt(nonce, encrypted_bytes.as_ref())
             .map_err(|e| ConfigError::Decryption(e.to_string()))?;
 
-        let settings: EmailSettings = serde_json::from_slice(&decrypted_bytes)?;
-        Ok(settings)
+        let value = migrate(
+            serde_json::from_slice(&decrypted_bytes)?,
+            encrypted_config.version,
+        )?;
+        let mut profiles: Profiles = serde_json::from_value(value)?;
+        profiles.repair();
+
+        if encrypted_config.version < CONFIG_VERSION
+            || matches!(encrypted_config.key, KeySource::Legacy)
+        {
+            self.save_settings(&profiles)?;
+        }
+
+        Ok(profiles)
     }
 
-    pub fn save_settings(&self, settings: &EmailSettings) -> Result<(), ConfigError> {
-        let cipher = Aes256Gcm::new_from_slice(&self.key)
-            .map_err(|e| ConfigError::Encryption(e.to_string()))?;
+    pub fn save_settings(&self, settings: &Profiles) -> Result<(), ConfigError> {
+        settings.validate()?;
+
+        let (key, key_source) = match &self.passphrase {
+            Some(passphrase) => {
+                let mut salt = [0u8; 16];
+                OsRng.fill_bytes(&mut salt);
+
+                let key = self
+                    .derive_key(passphrase, &salt)
+                    .map_err(|e| ConfigError::Encryption(e.to_string()))?;
+
+                (
+                    key,
+                    KeySource::Passphrase {
+                        salt: general_purpose::STANDARD.encode(salt),
+                    },
+                )
+            }
+            None => (self.key, KeySource::KeyFile),
+        };
+
+        let cipher =
+            Aes256Gcm::new_from_slice(&key).map_err(|e| ConfigError::Encryption(e.to_string()))?;
 
         let mut nonce_bytes = [0u8; 12];
         OsRng.fill_bytes(&mut nonce_bytes);
@@ -129,8 +786,10 @@ impl ConfigManager {
             .map_err(|e| ConfigError::Encryption(e.to_string()))?;
 
         let encrypted_config = EncryptedConfig {
+            version: CONFIG_VERSION,
             data: general_purpose::STANDARD.encode(&encrypted_bytes),
             nonce: general_purpose::STANDARD.encode(nonce),
+            key: key_source,
         };
 
         let encrypted_json = serde_json::to_string_pretty(&encrypted_config)?;
@@ -138,4 +797,391 @@ impl ConfigManager {
 
         Ok(())
     }
+
+    /// Saves refresh tokens rotated by the OAuth2 provider into the saved
+    /// settings, leaving any unsaved changes out.
+    pub fn save_refresh_tokens(&self, rotated: &[(String, String)]) -> Result<(), ConfigError> {
+        let mut profiles = self.load_settings()?;
+        if profiles.replace_refresh_tokens(rotated) {
+            self.save_settings(&profiles)?;
+        }
+
+        Ok(())
+    }
+
+    fn derive_key(&self, passphrase: &str, salt: &[u8]) -> Result<[u8; 32], argon2::Error> {
+        let argon2 = Argon2::new_with_secret(
+            &self.key,
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::default(),
+        )?;
+
+        let mut key = [0u8; 32];
+        argon2.hash_password_into(passphrase.as_bytes(), salt, &mut key)?;
+
+        Ok(key)
+    }
+}
+
+/// Reads the random key from `path`, creating it readable by the current
+/// user only if it doesn't exist yet.
+fn load_or_create_key(path: &Path) -> Result<[u8; 32], ConfigError> {
+    if path.exists() {
+        restrict_permissions(path)?;
+
+        return fs::read(path)?
+            .try_into()
+            .map_err(|_| ConfigError::Decryption(format!("invalid key file: {}", path.display())));
+    }
+
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(&key)?;
+    file.sync_all()?;
+
+    Ok(key)
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> Result<(), ConfigError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(path)?.permissions();
+    if permissions.mode() & 0o077 != 0 {
+        permissions.set_mode(0o600);
+        fs::set_permissions(path, permissions)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> Result<(), ConfigError> {
+    // The config directory is already private to the user on Windows
+    Ok(())
+}
+
+/// The key used before `secret.key` existed, derived from the username.
+fn legacy_key() -> [u8; 32] {
+    let machine_id = whoami::username();
+    let mut key = [0u8; 32];
+    let machine_bytes = machine_id.as_bytes();
+    for (i, &byte) in machine_bytes.iter().enumerate() {
+        if i >= 32 {
+            break;
+        }
+        key[i] = byte;
+    }
+
+    // Fill remaining bytes with a pattern if machine_id is short
+    for (i, slot) in key.iter_mut().enumerate().skip(machine_bytes.len()) {
+        *slot = (i as u8).wrapping_mul(7).wrapping_add(42);
+    }
+
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> (tempfile::TempDir, ConfigManager) {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = ConfigManager::in_dir(dir.path().join("config")).unwrap();
+        (dir, manager)
+    }
+
+    /// Writes `value` to `settings.json` the way `save_settings` would,
+    /// encrypted with `key`.
+    fn write_config(
+        manager: &ConfigManager,
+        value: Value,
+        version: u32,
+        key: [u8; 32],
+        source: KeySource,
+    ) {
+        let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
+        let nonce_bytes = [7u8; 12];
+        let data = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce_bytes),
+                serde_json::to_vec(&value).unwrap().as_ref(),
+            )
+            .unwrap();
+
+        let config = EncryptedConfig {
+            version,
+            data: general_purpose::STANDARD.encode(data),
+            nonce: general_purpose::STANDARD.encode(nonce_bytes),
+            key: source,
+        };
+        fs::write(
+            &manager.config_path,
+            serde_json::to_string(&config).unwrap(),
+        )
+        .unwrap();
+    }
+
+    fn saved_config(manager: &ConfigManager) -> EncryptedConfig {
+        serde_json::from_str(&fs::read_to_string(&manager.config_path).unwrap()).unwrap()
+    }
+
+    fn sample_profiles() -> Profiles {
+        let mut profiles = Profiles::default();
+        profiles.profiles[0].settings.from_email = "me@example.com".to_string();
+        profiles.profiles.push(Profile {
+            name: "Work".to_string(),
+            settings: EmailSettings {
+                smtp_host: "smtp.example.com".to_string(),
+                ..EmailSettings::default()
+            },
+        });
+        profiles
+    }
+
+    /// Settings from before profiles, as saved by the first builds.
+    fn v0_fixture() -> Value {
+        json!({
+            "smtp_host": "smtp.example.com",
+            "smtp_port": "465",
+            "from_email": "me@example.com",
+            "to_email": "inbox@goodnotes.email",
+            "app_password": "secret",
+            "max_message_size_mb": "25",
+        })
+    }
+
+    /// Profiles without a TLS mode.
+    fn v1_fixture() -> Value {
+        json!({
+            "profiles": [
+                { "name": "Default", "settings": { "smtp_host": "a", "smtp_port": "465" } },
+                { "name": "Work", "settings": { "smtp_host": "b", "smtp_port": "587" } },
+                {
+                    "name": "Relay",
+                    "settings": { "smtp_host": "c", "smtp_port": "25", "tls_mode": "Plaintext" },
+                },
+            ],
+            "default": "Work",
+        })
+    }
+
+    fn v2_fixture() -> Value {
+        serde_json::to_value(sample_profiles()).unwrap()
+    }
+
+    /// Migrates `value` from `version`, loads it and checks that saving and
+    /// loading it again doesn't change it.
+    fn round_trip(value: Value, version: u32) -> Profiles {
+        let profiles: Profiles = serde_json::from_value(migrate(value, version).unwrap()).unwrap();
+        let saved = serde_json::to_value(&profiles).unwrap();
+
+        let reloaded: Profiles =
+            serde_json::from_value(migrate(saved.clone(), CONFIG_VERSION).unwrap()).unwrap();
+        assert_eq!(serde_json::to_value(&reloaded).unwrap(), saved);
+
+        profiles
+    }
+
+    #[test]
+    fn migrates_v0() {
+        let profiles = round_trip(v0_fixture(), 0);
+
+        assert_eq!(profiles.default, DEFAULT_PROFILE_NAME);
+        assert_eq!(profiles.profiles.len(), 1);
+
+        let settings = &profiles.profiles[0].settings;
+        assert_eq!(profiles.profiles[0].name, DEFAULT_PROFILE_NAME);
+        assert_eq!(settings.smtp_host, "smtp.example.com");
+        assert_eq!(settings.from_email, "me@example.com");
+        assert_eq!(settings.tls_mode, TlsMode::Implicit);
+    }
+
+    #[test]
+    fn migrates_unversioned_profiles() {
+        // Later builds saved profiles before the version field existed
+        let profiles = round_trip(v1_fixture(), 0);
+        assert_eq!(profiles.names(), ["Default", "Work", "Relay"]);
+    }
+
+    #[test]
+    fn migrates_v1() {
+        let profiles = round_trip(v1_fixture(), 1);
+
+        assert_eq!(profiles.default, "Work");
+        let modes: Vec<TlsMode> = profiles
+            .profiles
+            .iter()
+            .map(|p| p.settings.tls_mode)
+            .collect();
+        assert_eq!(
+            modes,
+            [
+                TlsMode::Implicit,
+                TlsMode::StartTlsRequired,
+                TlsMode::Plaintext
+            ]
+        );
+    }
+
+    #[test]
+    fn v2_is_unchanged() {
+        assert_eq!(migrate(v2_fixture(), 2).unwrap(), v2_fixture());
+
+        let profiles = round_trip(v2_fixture(), 2);
+        assert_eq!(profiles.names(), ["Default", "Work"]);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        assert!(matches!(
+            migrate(v2_fixture(), CONFIG_VERSION + 1),
+            Err(ConfigError::UnsupportedVersion(v)) if v == CONFIG_VERSION + 1
+        ));
+
+        let (_dir, manager) = manager();
+        write_config(
+            &manager,
+            v2_fixture(),
+            CONFIG_VERSION + 1,
+            manager.key,
+            KeySource::KeyFile,
+        );
+        assert!(matches!(
+            manager.load_settings(),
+            Err(ConfigError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn repairs_empty_profiles() {
+        let (_dir, manager) = manager();
+        write_config(
+            &manager,
+            json!({ "profiles": [], "default": "Gone" }),
+            CONFIG_VERSION,
+            manager.key,
+            KeySource::KeyFile,
+        );
+
+        let profiles = manager.load_settings().unwrap();
+        assert_eq!(profiles.names(), [DEFAULT_PROFILE_NAME]);
+        assert_eq!(profiles.default, DEFAULT_PROFILE_NAME);
+        assert!(profiles.validate().is_ok());
+    }
+
+    #[test]
+    fn key_file_round_trip() {
+        let (_dir, manager) = manager();
+        manager.save_settings(&sample_profiles()).unwrap();
+
+        assert!(matches!(saved_config(&manager).key, KeySource::KeyFile));
+
+        // A new manager reads the same key file back
+        let reopened = ConfigManager::in_dir(manager.config_dir.clone()).unwrap();
+        let profiles = reopened.load_settings().unwrap();
+        assert_eq!(profiles.profiles.len(), 2);
+        assert_eq!(profiles.profiles[0].settings.from_email, "me@example.com");
+        assert_eq!(profiles.profiles[1].settings.smtp_host, "smtp.example.com");
+    }
+
+    #[test]
+    fn passphrase_is_required_and_checked() {
+        let (_dir, mut manager) = manager();
+        manager.set_passphrase(Some("correct horse".to_string()));
+        manager.save_settings(&sample_profiles()).unwrap();
+
+        assert!(matches!(
+            saved_config(&manager).key,
+            KeySource::Passphrase { .. }
+        ));
+
+        manager.set_passphrase(None);
+        assert!(matches!(
+            manager.load_settings(),
+            Err(ConfigError::PassphraseRequired)
+        ));
+
+        manager.set_passphrase(Some("wrong".to_string()));
+        assert!(matches!(
+            manager.load_settings(),
+            Err(ConfigError::Decryption(_))
+        ));
+
+        manager.set_passphrase(Some("correct horse".to_string()));
+        let profiles = manager.load_settings().unwrap();
+        assert_eq!(profiles.profiles[0].settings.from_email, "me@example.com");
+    }
+
+    #[test]
+    fn legacy_key_is_migrated_to_key_file() {
+        let (_dir, manager) = manager();
+        let settings = EmailSettings {
+            from_email: "old@example.com".to_string(),
+            ..EmailSettings::default()
+        };
+        write_config(
+            &manager,
+            serde_json::to_value(&settings).unwrap(),
+            0,
+            legacy_key(),
+            KeySource::Legacy,
+        );
+
+        let profiles = manager.load_settings().unwrap();
+        assert_eq!(profiles.profiles.len(), 1);
+        assert_eq!(profiles.profiles[0].name, DEFAULT_PROFILE_NAME);
+        assert_eq!(profiles.profiles[0].settings.from_email, "old@example.com");
+
+        let saved = saved_config(&manager);
+        assert!(matches!(saved.key, KeySource::KeyFile));
+        assert_eq!(saved.version, CONFIG_VERSION);
+        assert_eq!(
+            manager.load_settings().unwrap().profiles[0]
+                .settings
+                .from_email,
+            "old@example.com"
+        );
+    }
+
+    #[test]
+    fn folder_routing_tags_file_names_before_the_extension() {
+        let routing = FolderRouting::default();
+        assert_eq!(
+            routing.apply_to_name("Biology", "Cells.pdf"),
+            "[Biology] Cells.pdf"
+        );
+        assert_eq!(
+            routing.apply_to_name("Biology", "Cells"),
+            "[Biology] Cells.pdf"
+        );
+        assert_eq!(routing.apply_to_name("生物", "細胞.PDF"), "[生物] 細胞.pdf");
+
+        let routing = FolderRouting {
+            pattern: "{text} - {folder}".to_string(),
+            ..FolderRouting::default()
+        };
+        assert_eq!(
+            routing.apply_to_name("Biology", "Cells.pdf"),
+            "Cells - Biology.pdf"
+        );
+    }
+
+    #[test]
+    fn folder_routing_keeps_placeholders_in_folder_names() {
+        let routing = FolderRouting::default();
+        assert_eq!(routing.apply("{text}", "Notes"), "[{text}] Notes");
+        assert_eq!(routing.apply("Biology", "{folder}"), "[Biology] {folder}");
+    }
 }
@@ -1,13 +1,32 @@
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::Duration;
 use thiserror::Error;
 
-use lettre::Transport;
 use lettre::message::header::ContentType;
 use lettre::message::{Attachment, Message, MultiPart, SinglePart};
 use lettre::transport::smtp::SmtpTransport;
-use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::client::{Certificate, CertificateStore, Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
 
-use crate::config::EmailSettings;
+use sha2::{Digest, Sha256};
+
+use crate::config::{AuthMethod, EmailSettings, FolderRouting, RecipientMode, TlsMode};
+use crate::history::{self, SentFile};
+use crate::oauth::{self, OAuthError};
+use crate::queue;
+
+/// Maximum number of attachments sent in a single email.
+pub const MAX_ATTACHMENTS: usize = 5;
+
+// Rough upper bounds for the headers of the message itself and of each MIME
+// part, used when estimating the final message size.
+const MESSAGE_OVERHEAD: u64 = 2048;
+const PART_OVERHEAD: u64 = 512;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Error)]
 pub enum EmailError {
@@ -25,69 +44,286 @@ pub enum EmailError {
     InvalidPort(String),
     #[error("settings not configured properly")]
     IncompleteSettings,
+    #[error("invalid message size limit: {0}")]
+    InvalidSizeLimit(String),
+    #[error("{name} is too large to send: {size} bytes encoded, limit is {limit} bytes")]
+    AttachmentTooLarge { name: String, size: u64, limit: u64 },
+    #[error("message is too large to send: {size} bytes, limit is {limit} bytes")]
+    MessageTooLarge { size: usize, limit: u64 },
+    #[error("could not resolve host {0}")]
+    DnsLookup(String),
+    #[error("could not connect to {host}:{port}, the port is closed or blocked")]
+    PortClosed { host: String, port: u16 },
+    #[error("connection to {host}:{port} timed out")]
+    ConnectTimeout { host: String, port: u16 },
+    #[error("TLS handshake failed: {0}")]
+    TlsHandshake(String),
+    #[error("authentication rejected: {0}")]
+    AuthRejected(String),
+    #[error(transparent)]
+    OAuth(#[from] OAuthError),
+    #[error("could not load certificate {path}: {reason}")]
+    Certificate { path: String, reason: String },
+}
+
+impl EmailError {
+    /// Whether sending again later can't succeed without changing the files
+    /// or settings, e.g. a missing file or a recipient rejected with a 5xx
+    /// reply.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            EmailError::Io(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+            ),
+            EmailError::Smtp(e) => e.is_permanent(),
+            EmailError::DnsLookup(_)
+            | EmailError::PortClosed { .. }
+            | EmailError::ConnectTimeout { .. }
+            | EmailError::TlsHandshake(_) => false,
+            EmailError::OAuth(e) => !matches!(e, OAuthError::Http(_) | OAuthError::Io(_)),
+            EmailError::TooManyAttachments(_)
+            | EmailError::Build(_)
+            | EmailError::Addr(_)
+            | EmailError::InvalidPort(_)
+            | EmailError::IncompleteSettings
+            | EmailError::InvalidSizeLimit(_)
+            | EmailError::AttachmentTooLarge { .. }
+            | EmailError::MessageTooLarge { .. }
+            | EmailError::AuthRejected(_)
+            | EmailError::Certificate { .. } => true,
+        }
+    }
+}
+
+/// Why the email of a queued job was not sent.
+#[derive(Debug, Clone)]
+pub struct SendFailure {
+    pub error: String,
+    /// See [`EmailError::is_permanent`].
+    pub permanent: bool,
+}
+
+impl From<EmailError> for SendFailure {
+    fn from(e: EmailError) -> Self {
+        Self {
+            permanent: e.is_permanent(),
+            error: e.to_string(),
+        }
+    }
+}
+
+/// Step of [`send_pdfs`] that has just started.
+#[derive(Debug, Clone)]
+pub enum SendProgress {
+    /// Reading attachment `index` (starting at 0) of `total`.
+    Reading {
+        index: usize,
+        total: usize,
+        name: String,
+    },
+    /// Building and encoding the message.
+    Encoding,
+    /// Handing the encoded message of `bytes` bytes to the server.
+    Uploading { bytes: usize },
+}
+
+impl SendProgress {
+    /// Share of the message that is prepared, for a progress bar. `None`
+    /// while uploading, as lettre doesn't report how much has been sent.
+    pub fn fraction(&self) -> Option<f32> {
+        match self {
+            SendProgress::Reading { index, total, .. } => {
+                Some(*index as f32 / (*total).max(1) as f32)
+            }
+            SendProgress::Encoding => Some(1.0),
+            SendProgress::Uploading { .. } => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SendProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendProgress::Reading { index, total, name } => {
+                write!(f, "Reading {} ({} of {})", name, index + 1, total)
+            }
+            SendProgress::Encoding => f.write_str("Encoding attachments"),
+            SendProgress::Uploading { bytes } => {
+                write!(f, "Uploading {:.1} MB...", *bytes as f64 / (1024.0 * 1024.0))
+            }
+        }
+    }
 }
 
-pub fn send_pdfs(
+/// An email accepted by the SMTP server.
+#[derive(Debug, Clone)]
+pub struct SentEmail {
+    pub recipients: Vec<String>,
+    pub files: Vec<SentFile>,
+    /// Subject with its placeholders filled in, before it was tagged with
+    /// `folder`.
+    pub subject: String,
+    /// Destination folder the subject was tagged with.
+    pub folder: Option<String>,
+    /// Reply of the server to the message, e.g. `250 2.0.0 OK`.
+    pub response: String,
+}
+
+/// Groups `recipients` into the recipient lists of the emails to send,
+/// according to the profile's [`RecipientMode`].
+///
+/// An empty `recipients` list sends to the profile's own `to_email`.
+pub fn recipient_groups(settings: &EmailSettings, recipients: &[String]) -> Vec<Vec<String>> {
+    if recipients.is_empty() {
+        return vec![vec![settings.to_email.clone()]];
+    }
+
+    match settings.recipient_mode {
+        RecipientMode::Separate => recipients.iter().map(|r| vec![r.clone()]).collect(),
+        RecipientMode::Combined => vec![recipients.to_vec()],
+    }
+}
+
+/// `display_name` with a `.pdf` extension, as attachments are named.
+fn attachment_name(display_name: &str) -> String {
+    if display_name.to_lowercase().ends_with(".pdf") {
+        display_name.to_string()
+    } else {
+        format!("{display_name}.pdf")
+    }
+}
+
+/// Name of this computer, looked up once as templates are rendered on every
+/// redraw of the settings view.
+static HOSTNAME: LazyLock<String> =
+    LazyLock::new(|| whoami::fallible::hostname().unwrap_or_default());
+
+/// Fills in the placeholders of a subject or body template for an email
+/// with attachments called `names`, sent at `timestamp`:
+///
+/// - `{count}`: number of attachments
+/// - `{names}`: attachment names, separated by commas
+/// - `{date}`: day the email is sent, as `YYYY-MM-DD` in UTC
+/// - `{hostname}`: name of this computer
+pub fn render_template(template: &str, names: &[String], timestamp: u64) -> String {
+    let value = |placeholder: &str| match placeholder {
+        "{count}" => Some(names.len().to_string()),
+        "{names}" => Some(names.join(", ")),
+        "{date}" => Some(history::format_date(timestamp)),
+        "{hostname}" => Some(HOSTNAME.clone()),
+        _ => None,
+    };
+
+    // A single pass, so that placeholders in file names are left as they are
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(end) = rest.find('}')
+            && let Some(value) = value(&rest[..=end])
+        {
+            rendered.push_str(&value);
+            rest = &rest[end + 1..];
+        } else {
+            rendered.push('{');
+            rest = &rest[1..];
+        }
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+/// Sends `files` in a single email to every address in `recipients`,
+/// calling `progress` as each step starts.
+///
+/// `subject` replaces the profile's subject template when given. The
+/// rendered subject is then tagged with the destination folder in `folder`,
+/// so that placeholders in folder names are left as they are.
+pub async fn send_pdfs(
     files: Vec<(PathBuf, String)>,
     settings: &EmailSettings,
-) -> Result<usize, EmailError> {
-    if files.len() > 5 {
+    recipients: &[String],
+    subject: Option<&str>,
+    folder: Option<(&FolderRouting, &str)>,
+    mut progress: impl FnMut(SendProgress),
+) -> Result<SentEmail, EmailError> {
+    if files.len() > MAX_ATTACHMENTS {
         return Err(EmailError::TooManyAttachments(files.len()));
     }
 
-    // Validate settings
-    if settings.smtp_host.is_empty()
-        || settings.from_email.is_empty()
-        || settings.to_email.is_empty()
-        || settings.app_password.is_empty()
-    {
+    if recipients.is_empty() || recipients.iter().any(|r| r.trim().is_empty()) {
         return Err(EmailError::IncompleteSettings);
     }
 
-    let app_password = settings.app_password.replace(' ', "");
-    let creds = Credentials::new(settings.from_email.clone(), app_password);
-
-    let port_num: u16 = settings
-        .smtp_port
-        .parse()
-        .map_err(|_| EmailError::InvalidPort(settings.smtp_port.clone()))?;
+    // Refreshing an OAuth2 access token is a blocking HTTP request
+    let config = {
+        let settings = settings.clone();
+        tokio::task::spawn_blocking(move || transport_config(&settings))
+            .await
+            .map_err(|e| EmailError::Io(std::io::Error::other(e)))??
+    };
+    let mailer = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+        .port(config.port)
+        .tls(config.tls)
+        .credentials(config.credentials)
+        .authentication(config.mechanisms)
+        .build();
 
-    let mailer = if port_num == 465 {
-        SmtpTransport::relay(&settings.smtp_host)?
-            .port(465)
-            .credentials(creds)
-            .build()
-    } else {
-        SmtpTransport::starttls_relay(&settings.smtp_host)?
-            .port(port_num)
-            .credentials(creds)
-            .build()
+    let names: Vec<String> = files
+        .iter()
+        .map(|(_, name)| attachment_name(name))
+        .collect();
+    let timestamp = queue::now();
+    let rendered_subject = render_template(
+        subject.unwrap_or(&settings.subject_template),
+        &names,
+        timestamp,
+    );
+    let subject = match folder {
+        Some((routing, folder)) => routing.apply(folder, &rendered_subject),
+        None => rendered_subject.clone(),
     };
+    let mut body = render_template(&settings.body_template, &names, timestamp);
+    if body.trim().is_empty() && files.is_empty() {
+        body = String::from("No attachments provided.");
+    }
 
     let mut parts: Vec<SinglePart> = Vec::new();
-    if files.is_empty() {
+    let mut sent_files: Vec<SentFile> = Vec::new();
+    if !body.trim().is_empty() {
         parts.push(
             SinglePart::builder()
                 .header(ContentType::parse("text/plain; charset=utf-8").unwrap())
-                .body(String::from("No attachments provided.")),
+                .body(body),
         );
-    } else {
-        for (path, display_name) in &files {
-            let data = std::fs::read(path)?;
+    }
+    for (index, ((path, display_name), final_name)) in files.iter().zip(names).enumerate() {
+        progress(SendProgress::Reading {
+            index,
+            total: files.len(),
+            name: display_name.clone(),
+        });
+        let data = tokio::fs::read(path).await?;
 
-            let final_name = if !display_name.to_lowercase().ends_with(".pdf") {
-                format!("{display_name}.pdf")
-            } else {
-                display_name.clone()
-            };
+        sent_files.push(SentFile {
+            path: path.clone(),
+            name: display_name.clone(),
+            size: data.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(&data)),
+            sources: Vec::new(),
+        });
 
-            parts.push(
-                Attachment::new(final_name)
-                    .body(data, ContentType::parse("application/pdf").unwrap()),
-            );
-        }
+        parts.push(
+            Attachment::new(final_name).body(data, ContentType::parse("application/pdf").unwrap()),
+        );
     }
 
+    progress(SendProgress::Encoding);
+
     let mut iter = parts.into_iter();
     let first = iter.next().expect("at least one part");
     let mut mixed = MultiPart::mixed().singlepart(first);
@@ -95,28 +331,501 @@ pub fn send_pdfs(
         mixed = mixed.singlepart(p);
     }
 
-    let email = Message::builder()
-        .from(
-            settings
-                .from_email
-                .parse::<lettre::message::Mailbox>()
-                .map_err(|e| EmailError::Addr(e.to_string()))?,
-        )
-        .to(settings
-            .to_email
+    let mut builder = Message::builder().from(
+        settings
+            .from_email
             .parse::<lettre::message::Mailbox>()
-            .map_err(|e| EmailError::Addr(e.to_string()))?)
-        .subject("PDF files")
-        .multipart(mixed)?;
+            .map_err(|e| EmailError::Addr(e.to_string()))?,
+    );
+    for recipient in recipients {
+        builder = builder.to(recipient
+            .trim()
+            .parse::<lettre::message::Mailbox>()
+            .map_err(|e| EmailError::Addr(e.to_string()))?);
+    }
+
+    let email = builder.subject(subject).multipart(mixed)?;
+
+    let limit = message_size_limit(settings)?;
+    let formatted = email.formatted();
+    let size = formatted.len();
+    if size as u64 > limit {
+        return Err(EmailError::MessageTooLarge { size, limit });
+    }
+
+    progress(SendProgress::Uploading { bytes: size });
 
-    match mailer.send(&email) {
+    match mailer.send_raw(email.envelope(), &formatted).await {
         Ok(resp) => {
             println!("Email sent: code={:?}", resp.code());
-            Ok(files.len())
+            Ok(SentEmail {
+                recipients: recipients.iter().map(|r| r.trim().to_string()).collect(),
+                files: sent_files,
+                subject: rendered_subject,
+                folder: folder.map(|(_, folder)| folder.to_string()),
+                response: format!(
+                    "{} {}",
+                    resp.code(),
+                    resp.message().collect::<Vec<_>>().join(" ")
+                ),
+            })
         }
         Err(e) => {
             eprintln!("SMTP send error: {e}");
-            Err(EmailError::Smtp(e))
+            Err(classify_smtp_error(e))
         }
     }
 }
+
+/// Connection parameters shared by the blocking and the async transport.
+struct TransportConfig {
+    host: String,
+    port: u16,
+    tls: Tls,
+    credentials: Credentials,
+    mechanisms: Vec<Mechanism>,
+}
+
+/// Resolves the connection parameters for `settings`, refreshing the OAuth2
+/// access token if needed.
+fn transport_config(settings: &EmailSettings) -> Result<TransportConfig, EmailError> {
+    if settings.smtp_host.is_empty() || settings.from_email.is_empty() {
+        return Err(EmailError::IncompleteSettings);
+    }
+
+    let (credentials, mechanisms) = match settings.auth_method {
+        AuthMethod::Password => {
+            if settings.app_password.is_empty() {
+                return Err(EmailError::IncompleteSettings);
+            }
+
+            let app_password = settings.app_password.replace(' ', "");
+            (
+                Credentials::new(settings.from_email.clone(), app_password),
+                vec![Mechanism::Plain, Mechanism::Login],
+            )
+        }
+        AuthMethod::OAuth2 => {
+            let access_token = oauth::access_token(&settings.oauth)?;
+            (
+                Credentials::new(settings.from_email.clone(), access_token),
+                vec![Mechanism::Xoauth2],
+            )
+        }
+    };
+
+    let tls = match settings.tls_mode {
+        TlsMode::Implicit => Tls::Wrapper(tls_parameters(settings)?),
+        TlsMode::StartTlsRequired => Tls::Required(tls_parameters(settings)?),
+        TlsMode::StartTlsOpportunistic => Tls::Opportunistic(tls_parameters(settings)?),
+        TlsMode::Plaintext => Tls::None,
+    };
+
+    Ok(TransportConfig {
+        host: settings.smtp_host.trim().to_string(),
+        port: parse_port(settings)?,
+        tls,
+        credentials,
+        mechanisms,
+    })
+}
+
+/// Builds the blocking SMTP transport for `settings`.
+fn build_transport(settings: &EmailSettings) -> Result<SmtpTransport, EmailError> {
+    let config = transport_config(settings)?;
+
+    let mailer = SmtpTransport::builder_dangerous(&config.host)
+        .port(config.port)
+        .tls(config.tls)
+        .credentials(config.credentials)
+        .authentication(config.mechanisms)
+        .build();
+
+    Ok(mailer)
+}
+
+/// TLS parameters for the server, trusting the configured CA certificate
+/// or only the pinned server certificate.
+fn tls_parameters(settings: &EmailSettings) -> Result<TlsParameters, EmailError> {
+    let mut builder = TlsParameters::builder(settings.smtp_host.trim().to_string());
+
+    if !settings.ca_cert_path.trim().is_empty() {
+        builder = builder.add_root_certificate(read_certificate(&settings.ca_cert_path)?);
+    }
+
+    if !settings.pinned_cert_path.trim().is_empty() {
+        // A self-signed certificate rarely matches the name used to reach it
+        builder = builder
+            .certificate_store(CertificateStore::None)
+            .add_root_certificate(read_certificate(&settings.pinned_cert_path)?)
+            .dangerous_accept_invalid_hostnames(true);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn read_certificate(path: &str) -> Result<Certificate, EmailError> {
+    let path = path.trim();
+    let pem = std::fs::read(path).map_err(|e| EmailError::Certificate {
+        path: path.to_string(),
+        reason: e.to_string(),
+    })?;
+
+    Certificate::from_pem(&pem).map_err(|e| EmailError::Certificate {
+        path: path.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+fn parse_port(settings: &EmailSettings) -> Result<u16, EmailError> {
+    settings
+        .smtp_port
+        .trim()
+        .parse()
+        .map_err(|_| EmailError::InvalidPort(settings.smtp_port.clone()))
+}
+
+/// Maps TLS and authentication failures to their own variants.
+fn classify_smtp_error(e: lettre::transport::smtp::Error) -> EmailError {
+    if e.is_tls() {
+        return EmailError::TlsHandshake(e.to_string());
+    }
+
+    // 530 auth required, 534 mechanism too weak (e.g. app password needed),
+    // 535 credentials invalid, 454 temporary auth failure
+    let code = e.status().map(|c| c.to_string());
+    if matches!(code.as_deref(), Some("454" | "530" | "534" | "535")) {
+        return EmailError::AuthRejected(e.to_string());
+    }
+
+    EmailError::Smtp(e)
+}
+
+/// Maps a failed TCP connection to `host:port` to a timeout or a closed port.
+fn classify_connect_error(e: &std::io::Error, host: String, port: u16) -> EmailError {
+    match e.kind() {
+        std::io::ErrorKind::TimedOut => EmailError::ConnectTimeout { host, port },
+        _ => EmailError::PortClosed { host, port },
+    }
+}
+
+/// Connects and authenticates with the SMTP server without sending anything.
+///
+/// Each step is checked separately so failures come back as a specific
+/// error: host lookup, TCP connection, TLS handshake, then authentication.
+pub fn test_connection(settings: &EmailSettings) -> Result<(), EmailError> {
+    let mailer = build_transport(settings)?;
+    let host = settings.smtp_host.trim().to_string();
+    let port = parse_port(settings)?;
+
+    let addrs: Vec<SocketAddr> = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|_| EmailError::DnsLookup(host.clone()))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(EmailError::DnsLookup(host));
+    }
+
+    let mut last_error = None;
+    for addr in &addrs {
+        match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+            Ok(_) => {
+                last_error = None;
+                break;
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    if let Some(e) = last_error {
+        return Err(classify_connect_error(&e, host, port));
+    }
+
+    match mailer.test_connection() {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(EmailError::PortClosed { host, port }),
+        Err(e) if e.is_timeout() => Err(EmailError::ConnectTimeout { host, port }),
+        Err(e) => Err(classify_smtp_error(e)),
+    }
+}
+
+/// Parses the configured message size limit (in megabytes) into bytes.
+pub fn message_size_limit(settings: &EmailSettings) -> Result<u64, EmailError> {
+    settings
+        .max_message_size_mb
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|mb| mb.is_finite() && *mb > 0.0)
+        .map(|mb| (mb * 1024.0 * 1024.0) as u64)
+        .ok_or_else(|| EmailError::InvalidSizeLimit(settings.max_message_size_mb.clone()))
+}
+
+/// Whether a file of `len` bytes fits on its own in an email of at most
+/// `limit` bytes.
+pub fn attachment_fits(len: u64, limit: u64) -> bool {
+    MESSAGE_OVERHEAD + encoded_len(len) + PART_OVERHEAD <= limit
+}
+
+/// Size of `len` bytes once base64 encoded into 76 character lines.
+fn encoded_len(len: u64) -> u64 {
+    let encoded = len.div_ceil(3) * 4;
+    encoded + encoded.div_ceil(76) * 2
+}
+
+/// Splits `files` into consecutive batches that can each be sent with
+/// [`send_pdfs`], keeping the original order.
+///
+/// Each batch holds at most [`MAX_ATTACHMENTS`] files and its estimated
+/// encoded size stays under the limit configured in `settings`.
+pub fn split_into_batches(
+    files: Vec<(PathBuf, String)>,
+    settings: &EmailSettings,
+) -> Result<Vec<Vec<(PathBuf, String)>>, EmailError> {
+    let limit = message_size_limit(settings)?;
+
+    let mut batches: Vec<Vec<(PathBuf, String)>> = Vec::new();
+    let mut current: Vec<(PathBuf, String)> = Vec::new();
+    let mut current_size = MESSAGE_OVERHEAD;
+
+    for (path, display_name) in files {
+        let size = encoded_len(std::fs::metadata(&path)?.len()) + PART_OVERHEAD;
+
+        if MESSAGE_OVERHEAD + size > limit {
+            return Err(EmailError::AttachmentTooLarge {
+                name: display_name,
+                size,
+                limit,
+            });
+        }
+
+        if !current.is_empty()
+            && (current.len() >= MAX_ATTACHMENTS || current_size + size > limit)
+        {
+            batches.push(std::mem::take(&mut current));
+            current_size = MESSAGE_OVERHEAD;
+        }
+
+        current.push((path, display_name));
+        current_size += size;
+    }
+
+    if !current.is_empty() {
+        batches.push(current);
+    }
+
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Files of the given sizes in `dir`, named after their index.
+    fn files(dir: &std::path::Path, sizes: &[usize]) -> Vec<(PathBuf, String)> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| {
+                let path = dir.join(format!("{i}.pdf"));
+                std::fs::write(&path, vec![0u8; *size]).unwrap();
+                (path, format!("{i}.pdf"))
+            })
+            .collect()
+    }
+
+    fn settings(max_message_size_mb: &str) -> EmailSettings {
+        EmailSettings {
+            max_message_size_mb: max_message_size_mb.to_string(),
+            ..EmailSettings::default()
+        }
+    }
+
+    fn batch_sizes(batches: &[Vec<(PathBuf, String)>]) -> Vec<usize> {
+        batches.iter().map(Vec::len).collect()
+    }
+
+    #[test]
+    fn message_size_limit_is_read_in_megabytes() {
+        assert_eq!(
+            message_size_limit(&settings("25")).unwrap(),
+            25 * 1024 * 1024
+        );
+        assert_eq!(message_size_limit(&settings(" 0.5 ")).unwrap(), 512 * 1024);
+
+        for invalid in ["", "none", "0", "-1", "inf"] {
+            assert!(matches!(
+                message_size_limit(&settings(invalid)),
+                Err(EmailError::InvalidSizeLimit(_))
+            ));
+            assert!(matches!(
+                split_into_batches(Vec::new(), &settings(invalid)),
+                Err(EmailError::InvalidSizeLimit(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn batches_hold_at_most_max_attachments() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = files(dir.path(), &[100; 11]);
+
+        let batches = split_into_batches(files.clone(), &settings("25")).unwrap();
+
+        assert_eq!(batch_sizes(&batches), [5, 5, 1]);
+        assert_eq!(batches.concat(), files);
+    }
+
+    #[test]
+    fn batches_stay_under_the_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = files(dir.path(), &[30_000, 30_000, 30_000, 100, 30_000]);
+
+        // Room for two of the larger files in 0.1 MB, and the small one
+        let batches = split_into_batches(files.clone(), &settings("0.1")).unwrap();
+
+        assert_eq!(batch_sizes(&batches), [2, 3]);
+        assert_eq!(batches.concat(), files);
+    }
+
+    #[test]
+    fn files_over_the_limit_on_their_own_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = files(dir.path(), &[100, 100_000]);
+
+        match split_into_batches(files, &settings("0.1")) {
+            Err(EmailError::AttachmentTooLarge { name, .. }) => assert_eq!(name, "1.pdf"),
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    /// Plaintext SMTP server on a local port that answers the greeting and
+    /// then each command with the next of `replies`. The first connection,
+    /// the port check of [`test_connection`], is closed right away.
+    fn smtp_server(replies: Vec<&'static str>) -> u16 {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        std::thread::spawn(move || {
+            drop(listener.accept().unwrap());
+
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut writer = &stream;
+            writer.write_all(b"220 stub ESMTP\r\n").unwrap();
+            for reply in replies {
+                let mut command = String::new();
+                if reader.read_line(&mut command).unwrap_or(0) == 0 {
+                    break;
+                }
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        port
+    }
+
+    fn smtp_settings(port: u16) -> EmailSettings {
+        EmailSettings {
+            smtp_host: "127.0.0.1".to_string(),
+            smtp_port: port.to_string(),
+            from_email: "me@example.com".to_string(),
+            app_password: "secret".to_string(),
+            tls_mode: TlsMode::Plaintext,
+            ..EmailSettings::default()
+        }
+    }
+
+    const EHLO_REPLY: &str = "250-stub\r\n250 AUTH PLAIN LOGIN\r\n";
+
+    #[test]
+    fn connection_test_succeeds_once_logged_in() {
+        let port = smtp_server(vec![EHLO_REPLY, "235 2.7.0 accepted\r\n", "250 OK\r\n"]);
+
+        test_connection(&smtp_settings(port)).unwrap();
+    }
+
+    #[test]
+    fn rejected_logins_are_reported_as_such() {
+        for reply in [
+            "535 5.7.8 bad credentials\r\n",
+            "534 5.7.9 app password required\r\n",
+        ] {
+            let port = smtp_server(vec![EHLO_REPLY, reply]);
+
+            let error = test_connection(&smtp_settings(port)).unwrap_err();
+            assert!(matches!(error, EmailError::AuthRejected(_)), "{error:?}");
+            assert!(error.is_permanent());
+        }
+    }
+
+    #[test]
+    fn other_smtp_errors_keep_their_reply() {
+        let port = smtp_server(vec![EHLO_REPLY, "421 4.3.0 try again later\r\n"]);
+
+        let error = test_connection(&smtp_settings(port)).unwrap_err();
+        assert!(matches!(error, EmailError::Smtp(_)), "{error:?}");
+        assert!(!error.is_permanent());
+    }
+
+    #[test]
+    fn closed_ports_and_timeouts_are_told_apart() {
+        // Nothing listens on a port that was just released
+        let port = std::net::TcpListener::bind(("127.0.0.1", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        assert!(matches!(
+            test_connection(&smtp_settings(port)),
+            Err(EmailError::PortClosed { .. })
+        ));
+
+        let timed_out = std::io::Error::from(std::io::ErrorKind::TimedOut);
+        assert!(matches!(
+            classify_connect_error(&timed_out, "smtp.example.com".to_string(), 587),
+            EmailError::ConnectTimeout { port: 587, .. }
+        ));
+    }
+
+    #[test]
+    fn render_template_fills_in_placeholders() {
+        let names = vec!["Notes.pdf".to_string(), "Slides.pdf".to_string()];
+        // 2024-02-29 23:59:59 UTC
+        let timestamp = 1_709_251_199;
+
+        assert_eq!(
+            render_template("{count} files: {names} ({date})", &names, timestamp),
+            "2 files: Notes.pdf, Slides.pdf (2024-02-29)"
+        );
+        assert_eq!(
+            render_template("From {hostname}", &names, timestamp),
+            format!("From {}", *HOSTNAME)
+        );
+        assert_eq!(
+            render_template("{folder} {unknown}", &names, timestamp),
+            "{folder} {unknown}"
+        );
+        assert_eq!(render_template("{{count}}", &names, timestamp), "{2}");
+    }
+
+    #[test]
+    fn render_template_leaves_placeholders_in_file_names() {
+        let names = vec!["{date} notes.pdf".to_string(), "{count}.pdf".to_string()];
+
+        assert_eq!(
+            render_template("{names} on {date}", &names, 0),
+            "{date} notes.pdf, {count}.pdf on 1970-01-01"
+        );
+    }
+
+    #[test]
+    fn attachment_fits_counts_encoding_and_headers() {
+        // 30000 bytes are 41054 once base64 encoded into lines
+        let needed = MESSAGE_OVERHEAD + 41_054 + PART_OVERHEAD;
+
+        assert!(attachment_fits(30_000, needed));
+        assert!(!attachment_fits(30_000, needed - 1));
+    }
+}
@@ -0,0 +1,306 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::ConfigError;
+use crate::email::SentEmail;
+
+/// A file as it was attached to a sent email.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentFile {
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the file's contents.
+    pub sha256: String,
+    /// SHA-256 of the selected files the attachment was split, merged or
+    /// compressed from, empty if it was sent as selected.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+}
+
+impl SentFile {
+    /// Whether this is the file with hash `sha256`, or was made from it.
+    pub fn has_hash(&self, sha256: &str) -> bool {
+        self.sha256 == sha256 || self.sources.iter().any(|s| s == sha256)
+    }
+}
+
+/// A successfully sent email.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    /// Unix timestamp (seconds) of when the server accepted the email.
+    pub timestamp: u64,
+    /// Settings profile the email was sent with, `None` for the default.
+    pub profile: Option<String>,
+    pub recipients: Vec<String>,
+    pub files: Vec<SentFile>,
+    /// Subject with its placeholders filled in, before it was tagged with
+    /// `folder`. Empty for emails logged before subjects were.
+    #[serde(default)]
+    pub subject: String,
+    /// Destination folder the subject was tagged with, see
+    /// [`crate::config::FolderRouting`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    /// Reply of the SMTP server to the message.
+    pub response: String,
+}
+
+impl HistoryEntry {
+    /// Whether every attached file is still where it was sent from.
+    pub fn files_exist(&self) -> bool {
+        self.files.iter().all(|f| f.path.is_file())
+    }
+
+    /// Whether some attachments were made by splitting, merging or
+    /// compressing the selected files. These are deleted once sent, so the
+    /// email can't be sent again as it was.
+    pub fn has_prepared_files(&self) -> bool {
+        self.files.iter().any(|f| !f.sources.is_empty())
+    }
+
+    /// Case-insensitive match of `query` against the file names, paths,
+    /// hashes, recipients, subject, folder and profile.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return true;
+        }
+
+        let contains = |s: &str| s.to_lowercase().contains(&query);
+
+        self.files.iter().any(|f| {
+            contains(&f.name)
+                || contains(&f.path.to_string_lossy())
+                || contains(&f.sha256)
+                || f.sources.iter().any(|s| contains(s))
+        }) || self.recipients.iter().any(|r| contains(r))
+            || contains(&self.subject)
+            || self.folder.as_deref().is_some_and(contains)
+            || self.profile.as_deref().is_some_and(contains)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HistoryFile {
+    next_id: u64,
+    entries: Vec<HistoryEntry>,
+}
+
+/// Log of sent emails, stored as `history.json` in the config directory.
+#[derive(Debug, Default)]
+pub struct History {
+    path: Option<PathBuf>,
+    next_id: u64,
+    entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    pub fn load(path: PathBuf) -> Result<Self, ConfigError> {
+        let file = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            HistoryFile::default()
+        };
+
+        Ok(Self {
+            path: Some(path),
+            next_id: file.next_id,
+            entries: file.entries,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let file = HistoryFile {
+            next_id: self.next_id,
+            entries: self.entries.clone(),
+        };
+        fs::write(path, serde_json::to_string_pretty(&file)?)?;
+
+        Ok(())
+    }
+
+    /// Entries from oldest to newest.
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, id: u64) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    /// Latest entry that sent a file with this hash to any of `recipients`.
+    pub fn find_sent(&self, sha256: &str, recipients: &[String]) -> Option<&HistoryEntry> {
+        self.entries.iter().rev().find(|e| {
+            e.files.iter().any(|f| f.has_hash(sha256))
+                && e.recipients
+                    .iter()
+                    .any(|r| recipients.iter().any(|d| d.trim().eq_ignore_ascii_case(r)))
+        })
+    }
+
+    /// Adds an entry for `email`, sent at `timestamp`.
+    pub fn record(&mut self, email: SentEmail, profile: Option<String>, timestamp: u64) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.entries.push(HistoryEntry {
+            id,
+            timestamp,
+            profile,
+            recipients: email.recipients,
+            files: email.files,
+            subject: email.subject,
+            folder: email.folder,
+            response: email.response,
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Hex encoded SHA-256 of the contents of the file at `path`.
+pub fn file_sha256(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Formats a Unix timestamp as `YYYY-MM-DD HH:MM` in UTC.
+pub fn format_timestamp(timestamp: u64) -> String {
+    let secs = timestamp % 86_400;
+
+    format!(
+        "{} {:02}:{:02}",
+        format_date(timestamp),
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+/// Formats the day of a Unix timestamp as `YYYY-MM-DD` in UTC.
+pub fn format_date(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent_file(sha256: &str, sources: &[&str]) -> SentFile {
+        SentFile {
+            path: PathBuf::from("notes.pdf"),
+            name: "notes.pdf".to_string(),
+            size: 1,
+            sha256: sha256.to_string(),
+            sources: sources.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn sent_email(recipient: &str, files: Vec<SentFile>) -> SentEmail {
+        SentEmail {
+            recipients: vec![recipient.to_string()],
+            files,
+            subject: "PDF files".to_string(),
+            folder: None,
+            response: "250 OK".to_string(),
+        }
+    }
+
+    #[test]
+    fn find_sent_matches_hash_and_recipient() {
+        let mut history = History::default();
+        history.record(
+            sent_email("ipad@goodnotes.email", vec![sent_file("aaa", &[])]),
+            None,
+            1,
+        );
+        history.record(
+            sent_email("mac@goodnotes.email", vec![sent_file("aaa", &[])]),
+            None,
+            2,
+        );
+
+        let ipad = vec![" IPad@GoodNotes.email ".to_string()];
+        assert_eq!(
+            history.find_sent("aaa", &ipad).map(|e| e.timestamp),
+            Some(1)
+        );
+        assert!(history.find_sent("bbb", &ipad).is_none());
+        assert!(
+            history
+                .find_sent("aaa", &["other@goodnotes.email".to_string()])
+                .is_none()
+        );
+
+        // The latest matching send
+        let both = vec!["mac@goodnotes.email".to_string(), ipad[0].clone()];
+        assert_eq!(
+            history.find_sent("aaa", &both).map(|e| e.timestamp),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn find_sent_matches_files_prepared_from_the_hash() {
+        let mut history = History::default();
+        let merged = sent_file("merged", &["aaa", "bbb"]);
+        history.record(sent_email("ipad@goodnotes.email", vec![merged]), None, 1);
+
+        let ipad = vec!["ipad@goodnotes.email".to_string()];
+        assert!(history.find_sent("bbb", &ipad).is_some());
+        assert!(history.find_sent("merged", &ipad).is_some());
+        assert!(history.find_sent("ccc", &ipad).is_none());
+    }
+
+    #[test]
+    fn older_entries_load_without_subject_or_folder() {
+        let entry: HistoryEntry = serde_json::from_str(
+            r#"{"id": 0, "timestamp": 1, "profile": null, "recipients": [], "files": [], "response": "250 OK"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(entry.subject, "");
+        assert_eq!(entry.folder, None);
+    }
+
+    #[test]
+    fn formats_dates_in_utc() {
+        assert_eq!(format_date(0), "1970-01-01");
+        // Leap days, the last second of a day, and 2100 not being a leap year
+        assert_eq!(format_date(951_782_400), "2000-02-29");
+        assert_eq!(format_date(1_709_251_199), "2024-02-29");
+        assert_eq!(format_date(1_709_251_200), "2024-03-01");
+        assert_eq!(format_date(4_107_542_400), "2100-03-01");
+    }
+
+    #[test]
+    fn formats_timestamps_to_the_minute() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1_709_251_199), "2024-02-29 23:59");
+        assert_eq!(format_timestamp(1_735_732_800), "2025-01-01 12:00");
+    }
+}
@@ -1,14 +1,28 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod app;
+mod cli;
 mod config;
 mod email;
+mod history;
+mod oauth;
+mod pdf;
+mod queue;
+mod watch;
 
 use app::{AppState, Message};
+use config::{FolderTarget, ImageFit, PageSize};
+use iced::futures::{SinkExt, StreamExt};
 use iced::{Event, Result as IcedResult, Size, Subscription, Task, window};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub fn main() -> IcedResult {
+    if let Some(code) = cli::run(std::env::args().skip(1).collect()) {
+        std::process::exit(code);
+    }
+
     iced::application("Send to Goodnotes", update, view)
         .centered()
         .window_size(Size::new(800.0, 600.0))
@@ -20,13 +34,44 @@ pub fn main() -> IcedResult {
 
             #[allow(clippy::collapsible_if)]
             if let Some(ref manager) = config_manager {
-                if let Ok(settings) = manager.load_settings() {
-                    state.settings = settings;
+                match manager.load_settings() {
+                    Ok(profiles) => state.profiles = profiles,
+                    Err(config::ConfigError::PassphraseRequired) => state.locked = true,
+                    Err(e) => state.status = Some(format!("Error loading settings: {}", e)),
+                }
+
+                match queue::Outbox::load(manager.outbox_path()) {
+                    Ok(outbox) => state.outbox = outbox,
+                    // Start over with an empty queue that is still saved,
+                    // keeping the unreadable one aside
+                    Err(e) => {
+                        let error = format!("Error loading queue: {}", e);
+                        let replaced = queue::Outbox::replace_unreadable(manager.outbox_path());
+                        state.status = Some(match replaced {
+                            Ok((outbox, broken)) => {
+                                state.outbox = outbox;
+                                match broken {
+                                    Some(broken) => {
+                                        format!("{error}, moved it to {}", broken.display())
+                                    }
+                                    None => error,
+                                }
+                            }
+                            Err(e) => format!("{error}, new emails won't be kept: {e}"),
+                        });
+                    }
+                }
+
+                match history::History::load(manager.history_path()) {
+                    Ok(history) => state.history = history,
+                    Err(e) => state.status = Some(format!("Error loading history: {}", e)),
                 }
             }
             state.config_manager = config_manager;
+            state.send_profile = state.profiles.default.clone();
 
-            (state, Task::none())
+            // Resume anything left in the outbox from a previous run
+            (state, Task::done(Message::Tick))
         })
 }
 
@@ -35,85 +80,510 @@ fn update(state: &mut AppState, message: Message) -> Task<Message> {
         Message::OpenFiles => Task::perform(
             async move {
                 rfd::FileDialog::new()
+                    .add_filter("PDF and images", &["pdf", "png", "jpg", "jpeg"])
                     .add_filter("PDF", &["pdf"])
-                    .set_title("Select PDF file(s)")
+                    .add_filter("Images", &pdf::IMAGE_EXTENSIONS)
+                    .set_title("Select PDF or image file(s)")
                     .pick_files()
             },
             Message::FilesPicked,
         ),
-        Message::FilesPicked(selection) => {
-            if let Some(files) = selection {
-                add_files_to_state(state, files);
+        Message::FilesPicked(selection) => match selection {
+            Some(files) => add_files_to_state(state, files),
+            None => Task::none(),
+        },
+        Message::FilesDropped(files) => add_files_to_state(state, files),
+        Message::ImagesConverted(result) => match result {
+            Ok(path) => add_files_to_state(state, vec![path]),
+            Err(e) => {
+                state.status = Some(format!("Error: could not convert images: {}", e));
+                Task::none()
+            }
+        },
+        Message::FileHashed(id, result) => {
+            if let Some(file) = state.file_mut(id) {
+                match result {
+                    Ok(hash) => file.hash = Some(hash),
+                    Err(e) => {
+                        let status =
+                            format!("Error: could not read {}: {}", file.path.display(), e);
+                        state.status = Some(status);
+                    }
+                }
+            }
+            Task::none()
+        }
+        Message::FileValidated(id, validation) => {
+            if let Some(file) = state.file_mut(id) {
+                file.check = Some(validation);
+            }
+            Task::none()
+        }
+        Message::FilePreviewed(id, modified, result) => {
+            // Failures leave the row without a thumbnail, checking the file
+            // reports what is wrong with it
+            if let Some(path) = state.file(id).map(|f| f.path.clone())
+                && let Ok(preview) = result
+            {
+                state.previews.insert(
+                    path,
+                    app::FilePreview {
+                        thumbnail: iced::widget::image::Handle::from_bytes(preview.thumbnail),
+                        pages: preview.pages,
+                        size: preview.size,
+                        modified,
+                    },
+                );
             }
             Task::none()
         }
-        Message::FilesDropped(files) => {
-            add_files_to_state(state, files);
+        Message::AllowDuplicatesToggled(allow) => {
+            state.allow_duplicates = allow;
             Task::none()
         }
         Message::Send => {
-            if state.sending {
+            if let Some(id) = state.editing_file.take() {
+                commit_edit(state, id);
+            }
+
+            if state.files.iter().any(|f| f.check.is_none()) {
+                state.status = Some("Error: files are still being checked".to_string());
+                return Task::none();
+            }
+            let invalid = state.invalid_count();
+            if invalid > 0 {
+                state.status = Some(format!(
+                    "Error: {} file(s) are encrypted or corrupt, remove them to send",
+                    invalid
+                ));
+                return Task::none();
+            }
+            // Duplicates can't be detected until every file is hashed
+            if state.files.iter().any(|f| f.hash.is_none()) {
+                state.status = Some("Error: files are still being checked".to_string());
                 return Task::none();
             }
 
-            if state.selected_files.len() > 5 {
+            let duplicates = state.duplicate_count();
+            if duplicates > 0 && !state.allow_duplicates {
                 state.status = Some(format!(
-                    "Too many attachments: {} (max 5)",
-                    state.selected_files.len()
+                    "Error: {} file(s) were already sent or queued for this address, check \"Send duplicates anyway\" to send them again",
+                    duplicates
                 ));
                 return Task::none();
             }
 
-            if let Some(i) = state.editing_index.take() {
-                commit_edit(state, i);
+            let mut page_ranges = Vec::new();
+            for file in &state.files {
+                match pdf::parse_page_ranges(&file.page_ranges) {
+                    Ok(ranges) => page_ranges.push(ranges),
+                    Err(e) => {
+                        state.status = Some(format!("Error: {}: {}", file.name, e));
+                        return Task::none();
+                    }
+                }
             }
 
-            state.sending = true;
-            state.status = Some("Sending...".into());
+            let compression = if state.profiles.compression.enabled {
+                let limit = state
+                    .send_profile()
+                    .ok_or(email::EmailError::IncompleteSettings)
+                    .and_then(|p| email::message_size_limit(&p.settings))
+                    .map_err(|e| e.to_string());
+                match state
+                    .profiles
+                    .compression
+                    .parse()
+                    .and_then(|(dpi, quality)| limit.map(|limit| (dpi, quality, limit)))
+                {
+                    Ok(compression) => Some(compression),
+                    Err(e) => {
+                        state.status = Some(format!("Error: {}", e));
+                        return Task::none();
+                    }
+                }
+            } else {
+                None
+            };
 
-            let files_with_names: Vec<(PathBuf, String)> = state
-                .selected_files
-                .iter()
-                .cloned()
-                .zip(state.file_names.clone())
-                .collect();
+            state.allow_duplicates = false;
+            state.optimized.clear();
 
-            let settings = state.settings.clone();
-            Task::perform(
-                async move { email::send_pdfs(files_with_names, &settings).map_err(|e| e.to_string()) },
-                Message::Sent,
-            )
+            let subject = state.take_subject_override();
+            let mut tasks = Vec::new();
+
+            for group in group_by_folder(state, page_ranges) {
+                let sources = group
+                    .files
+                    .iter()
+                    .zip(&group.hashes)
+                    .map(|((path, _), hash)| (path.clone(), vec![hash.clone()]))
+                    .collect();
+                let preparation = Preparation {
+                    merge: state.merge_files && group.files.len() > 1,
+                    page_ranges: group.page_ranges,
+                    hashes: group.hashes,
+                    compression,
+                };
+                if preparation.is_needed() {
+                    state.status = Some("Preparing PDFs...".to_string());
+                    tasks.push(prepare_files(
+                        group.files,
+                        preparation,
+                        subject.clone(),
+                        group.folder,
+                    ));
+                } else {
+                    tasks.push(enqueue_files(
+                        state,
+                        group.files,
+                        sources,
+                        subject.clone(),
+                        group.folder,
+                    ));
+                }
+            }
+
+            Task::batch(tasks)
+        }
+        Message::MergeToggled(merge) => {
+            state.merge_files = merge;
+            Task::none()
+        }
+        Message::FilesPrepared(subject, folder, result) => match result {
+            Ok(prepared) => {
+                state.optimized.extend(prepared.optimized);
+                enqueue_files(
+                    state,
+                    prepared.attachments,
+                    prepared.sources,
+                    subject,
+                    folder,
+                )
+            }
+            Err(e) => {
+                state.status = Some(format!("Error: {}", e));
+                Task::none()
+            }
+        },
+        Message::PageRangesChanged(id, value) => {
+            if let Some(file) = state.file_mut(id) {
+                file.page_ranges = value;
+            }
+            Task::none()
+        }
+        Message::SendProgress(id, progress) => {
+            if state.sending_job == Some(id) {
+                state.send_progress = Some(progress);
+            }
+            Task::none()
         }
-        Message::Sent(result) => {
+        Message::Sent(id, result) => {
             state.sending = false;
-            state.status = Some(match result {
-                Ok(c) => format!("Sent {} attachment(s)", c),
-                Err(e) => format!("Error: {}", e),
+            state.sending_job = None;
+            state.send_handle = None;
+            state.send_progress = None;
+
+            match result {
+                Ok(email) => {
+                    let profile = state
+                        .outbox
+                        .jobs()
+                        .iter()
+                        .find(|j| j.id == id)
+                        .and_then(|j| j.profile.clone());
+
+                    state.outbox.mark_sent(id);
+                    forget_removed_conversions(state);
+                    state.sent_count += email.files.len();
+                    state.sent_emails += 1;
+
+                    state.history.record(email, profile, queue::now());
+                    if let Err(e) = state.history.save() {
+                        state.status = Some(format!("Error saving history: {}", e));
+                    }
+                }
+                Err(e) => {
+                    let retrying =
+                        state
+                            .outbox
+                            .mark_failed(id, e.error.clone(), e.permanent, queue::now());
+                    state.status = Some(if retrying {
+                        format!("Error: {} (will retry, see Queue)", e.error)
+                    } else if e.permanent {
+                        format!("Error: {} (not retried, see Queue)", e.error)
+                    } else {
+                        format!(
+                            "Error: {} (gave up after {} attempts)",
+                            e.error,
+                            queue::MAX_ATTEMPTS
+                        )
+                    });
+                }
+            }
+
+            if let Err(e) = state.outbox.save() {
+                state.status = Some(format!("Error saving queue: {}", e));
+            }
+
+            let task = Task::batch([save_rotated_refresh_tokens(state), process_queue(state)]);
+
+            if !state.sending && state.sent_emails > 0 {
+                let waiting = state.outbox.pending_count();
+                let mut summary = if state.sent_emails > 1 {
+                    format!(
+                        "Sent {} attachment(s) in {} emails",
+                        state.sent_count, state.sent_emails
+                    )
+                } else {
+                    format!("Sent {} attachment(s)", state.sent_count)
+                };
+                if waiting > 0 {
+                    summary.push_str(&format!(", {} email(s) waiting to retry", waiting));
+                }
+
+                if !matches!(&state.status, Some(s) if s.starts_with("Error")) {
+                    state.status = Some(summary);
+                }
+                state.sent_count = 0;
+                state.sent_emails = 0;
+            }
+
+            task
+        }
+        Message::CancelSend => {
+            if let Some(handle) = state.send_handle.take() {
+                handle.abort();
+            }
+            state.sending = false;
+            state.sending_job = None;
+            state.send_progress = None;
+            state.sent_count = 0;
+            state.sent_emails = 0;
+
+            state.outbox.cancel_pending();
+            state.status = Some(match state.outbox.save() {
+                Ok(()) => "Sending cancelled, retry from the Queue to send again".to_string(),
+                Err(e) => format!("Error saving queue: {}", e),
             });
 
             Task::none()
         }
-        Message::Remove(path) => {
-            if let Some(idx) = state.selected_files.iter().position(|p| p == &path) {
-                state.selected_files.remove(idx);
-                state.file_names.remove(idx);
+        Message::Tick => process_queue(state),
+        Message::OpenQueue => {
+            state.show_queue = true;
+            Task::none()
+        }
+        Message::CloseQueue => {
+            state.show_queue = false;
+            Task::none()
+        }
+        Message::RetryJob(id) => {
+            state.outbox.retry(id);
+            if let Err(e) = state.outbox.save() {
+                state.status = Some(format!("Error saving queue: {}", e));
+            }
+
+            process_queue(state)
+        }
+        Message::RemoveJob(id) => {
+            if state.sending_job != Some(id) {
+                state.outbox.remove(id);
+                forget_removed_conversions(state);
+                if let Err(e) = state.outbox.save() {
+                    state.status = Some(format!("Error saving queue: {}", e));
+                }
+            }
+
+            Task::none()
+        }
+        Message::ClearSentJobs => {
+            state.outbox.clear_sent();
+            if let Err(e) = state.outbox.save() {
+                state.status = Some(format!("Error saving queue: {}", e));
+            }
 
-                if matches!(state.editing_index, Some(ei) if ei == idx) {
-                    state.editing_index = None;
+            Task::none()
+        }
+        Message::ToggleWatch(watching) => {
+            state.watching = watching;
+            if !watching {
+                state.watch_prompts.clear();
+            }
+            Task::none()
+        }
+        Message::WatchedFileReady(path) => {
+            if state.profiles.watch_confirm {
+                if !state.watch_prompts.contains(&path) {
+                    state.watch_prompts.push(path);
                 }
+                Task::none()
+            } else {
+                let name = default_display_name(&path);
+                enqueue_files(state, vec![(path, name)], HashMap::new(), None, None)
+            }
+        }
+        Message::WatchedFileInvalid(path, validation) => {
+            state.status = Some(format!(
+                "Error: {} was not sent, {}",
+                path.display(),
+                validation
+            ));
+            Task::none()
+        }
+        Message::WatchFailed(e) => {
+            state.watching = false;
+            state.status = Some(format!("Error: could not watch folders: {}", e));
+            Task::none()
+        }
+        Message::ConfirmWatched(path) => {
+            state.watch_prompts.retain(|p| p != &path);
+            let name = default_display_name(&path);
+            enqueue_files(state, vec![(path, name)], HashMap::new(), None, None)
+        }
+        Message::IgnoreWatched(path) => {
+            state.watch_prompts.retain(|p| p != &path);
+            Task::none()
+        }
+        Message::AddWatchFolder => Task::perform(
+            async move {
+                rfd::FileDialog::new()
+                    .set_title("Select folder to watch")
+                    .pick_folder()
+                    .map(|p| p.to_string_lossy().into_owned())
+            },
+            Message::WatchFolderAdded,
+        ),
+        Message::WatchFolderAdded(folder) => {
+            if let Some(folder) = folder
+                && !state.profiles.watch_folders.contains(&folder)
+            {
+                state.profiles.watch_folders.push(folder);
+                state.settings_changed = true;
+            }
+            Task::none()
+        }
+        Message::RemoveWatchFolder(i) => {
+            if i < state.profiles.watch_folders.len() {
+                state.profiles.watch_folders.remove(i);
+                state.settings_changed = true;
+            }
+            Task::none()
+        }
+        Message::WatchConfirmToggled(confirm) => {
+            state.profiles.watch_confirm = confirm;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::OpenHistory => {
+            state.show_history = true;
+            Task::none()
+        }
+        Message::CloseHistory => {
+            state.show_history = false;
+            Task::none()
+        }
+        Message::HistorySearchChanged(value) => {
+            state.history_search = value;
+            Task::none()
+        }
+        Message::Resend(id) => {
+            let Some(entry) = state.history.get(id) else {
+                return Task::none();
+            };
+
+            if entry.has_prepared_files() {
+                state.status = Some(
+                    "Error: the files were split, merged or compressed for this email, add them again to resend"
+                        .to_string(),
+                );
+                return Task::none();
+            }
+            if !entry.files_exist() {
+                state.status = Some("Error: some of the files no longer exist".to_string());
+                return Task::none();
+            }
+
+            let files = entry
+                .files
+                .iter()
+                .map(|f| (f.path.clone(), f.name.clone()))
+                .collect();
+            let sources = entry
+                .files
+                .iter()
+                .map(|f| {
+                    let hashes = if f.sources.is_empty() {
+                        vec![f.sha256.clone()]
+                    } else {
+                        f.sources.clone()
+                    };
+                    (f.path.clone(), hashes)
+                })
+                .collect();
+            state
+                .outbox
+                .enqueue(
+                    files,
+                    entry.profile.clone(),
+                    entry.recipients.clone(),
+                    (!entry.subject.is_empty()).then(|| entry.subject.clone()),
+                    entry.folder.clone(),
+                    sources,
+                );
+            state.show_history = false;
+
+            if let Err(e) = state.outbox.save() {
+                state.status = Some(format!("Error saving queue: {}", e));
+            }
+
+            process_queue(state)
+        }
+        Message::ClearHistory => {
+            state.history.clear();
+            if let Err(e) = state.history.save() {
+                state.status = Some(format!("Error saving history: {}", e));
+            }
+
+            Task::none()
+        }
+        Message::Remove(id) => {
+            if let Some(index) = state.file_index(id) {
+                let file = state.files.remove(index);
+                remove_conversion(state, &file.path);
+            }
+
+            if state.editing_file == Some(id) {
+                state.editing_file = None;
             }
 
             Task::none()
         }
-        Message::EditName(i) => {
-            if i < state.file_names.len() {
-                if let Some(prev) = state.editing_index.take() {
+        Message::MoveUp(id) => {
+            if let Some(i) = state.file_index(id)
+                && i > 0
+            {
+                state.files.swap(i - 1, i);
+            }
+            Task::none()
+        }
+        Message::MoveDown(id) => {
+            if let Some(i) = state.file_index(id)
+                && i + 1 < state.files.len()
+            {
+                state.files.swap(i, i + 1);
+            }
+            Task::none()
+        }
+        Message::EditName(id) => {
+            if state.file(id).is_some() {
+                if let Some(prev) = state.editing_file.take() {
                     commit_edit(state, prev);
                 }
 
-                state.editing_index = Some(i);
+                state.editing_file = Some(id);
 
-                let mut base = state.file_names[i].clone();
+                let mut base = state.file(id).map(|f| f.name.clone()).unwrap_or_default();
                 if base.len() >= 4 && base[base.len() - 4..].eq_ignore_ascii_case(".pdf") {
                     base.truncate(base.len() - 4);
                 }
@@ -132,28 +602,30 @@ fn update(state: &mut AppState, message: Message) -> Task<Message> {
             Task::none()
         }
         Message::CommitEdit => {
-            if let Some(i) = state.editing_index.take() {
-                commit_edit(state, i);
+            if let Some(id) = state.editing_file.take() {
+                commit_edit(state, id);
             }
 
             Task::none()
         }
         Message::CancelEdit => {
-            state.editing_index = None;
+            state.editing_file = None;
             state.editing_buffer.clear();
 
             Task::none()
         }
         Message::Clear => {
-            state.selected_files.clear();
-            state.file_names.clear();
-            state.editing_index = None;
+            for file in std::mem::take(&mut state.files) {
+                remove_conversion(state, &file.path);
+            }
+            state.editing_file = None;
             state.editing_buffer.clear();
             state.status = Some("Files cleared".to_string());
             Task::none()
         }
         Message::OpenSettings => {
             state.show_settings = true;
+            state.load_body_template();
             Task::none()
         }
         Message::CloseSettings => {
@@ -161,35 +633,488 @@ fn update(state: &mut AppState, message: Message) -> Task<Message> {
             Task::none()
         }
         Message::SmtpHostChanged(value) => {
-            state.settings.smtp_host = value;
+            state.edited_settings_mut().smtp_host = value;
             state.settings_changed = true;
             Task::none()
         }
         Message::SmtpPortChanged(value) => {
-            state.settings.smtp_port = value;
+            state.edited_settings_mut().smtp_port = value;
             state.settings_changed = true;
             Task::none()
         }
         Message::FromEmailChanged(value) => {
-            state.settings.from_email = value;
+            state.edited_settings_mut().from_email = value;
             state.settings_changed = true;
             Task::none()
         }
         Message::ToEmailChanged(value) => {
-            state.settings.to_email = value;
+            state.edited_settings_mut().to_email = value;
             state.settings_changed = true;
             Task::none()
         }
         Message::AppPasswordChanged(value) => {
-            state.settings.app_password = value;
+            state.edited_settings_mut().app_password = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::SubjectTemplateChanged(value) => {
+            state.edited_settings_mut().subject_template = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::BodyTemplateEdited(action) => {
+            let is_edit = action.is_edit();
+            state.body_template.perform(action);
+
+            if is_edit {
+                // The editor always ends its text with a newline
+                let text = state.body_template.text();
+                state.edited_settings_mut().body_template =
+                    text.strip_suffix('\n').unwrap_or(&text).to_string();
+                state.settings_changed = true;
+            }
+            Task::none()
+        }
+        Message::SubjectOverrideChanged(value) => {
+            state.subject_override = value;
+            Task::none()
+        }
+        Message::SendFolderSelected(folder) => {
+            state.send_folder = folder;
+            Task::none()
+        }
+        Message::FileFolderSelected(id, folder) => {
+            if let Some(file) = state.file_mut(id) {
+                file.folder = folder;
+            }
+            Task::none()
+        }
+        Message::FolderPresetInputChanged(value) => {
+            state.new_folder_preset = value;
+            Task::none()
+        }
+        Message::AddFolderPreset => {
+            let folder = state.new_folder_preset.trim().to_string();
+            if !folder.is_empty() && !state.profiles.folder_routing.presets.contains(&folder) {
+                state.profiles.folder_routing.presets.push(folder);
+                state.settings_changed = true;
+            }
+            state.new_folder_preset.clear();
+            Task::none()
+        }
+        Message::RemoveFolderPreset(i) => {
+            if i < state.profiles.folder_routing.presets.len() {
+                let folder = state.profiles.folder_routing.presets.remove(i);
+                state.settings_changed = true;
+
+                // Nothing can be tagged with a folder that can't be picked
+                if state.send_folder.as_ref() == Some(&folder) {
+                    state.send_folder = None;
+                }
+                for file in &mut state.files {
+                    if file.folder.as_ref() == Some(&folder) {
+                        file.folder = None;
+                    }
+                }
+            }
+            Task::none()
+        }
+        Message::FolderTargetChanged(target) => {
+            state.profiles.folder_routing.target = target;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::FolderPatternChanged(value) => {
+            state.profiles.folder_routing.pattern = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::MaxMessageSizeChanged(value) => {
+            state.edited_settings_mut().max_message_size_mb = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::SelectSendProfile(name) => {
+            state.send_profile = name;
+            Task::none()
+        }
+        Message::SelectEditedProfile(name) => {
+            if let Some(i) = state.profiles.profiles.iter().position(|p| p.name == name) {
+                state.editing_profile = i;
+                state.profile_name_buffer = None;
+                state.load_body_template();
+                state.connection_test = None;
+                state.oauth_status = None;
+            }
+            Task::none()
+        }
+        Message::ProfileNameChanged(value) => {
+            state.profile_name_buffer = Some(value);
+            Task::none()
+        }
+        Message::RenameProfile => {
+            let Some(value) = state.profile_name_buffer.clone() else {
+                return Task::none();
+            };
+            if state.profile_rename_error(&value).is_some() {
+                return Task::none();
+            }
+            let value = value.trim().to_string();
+            state.profile_name_buffer = None;
+
+            let old = std::mem::replace(
+                &mut state.profiles.profiles[state.editing_profile].name,
+                value.clone(),
+            );
+            if old == value {
+                return Task::none();
+            }
+            if state.profiles.default == old {
+                state.profiles.default = value.clone();
+            }
+            if state.send_profile == old {
+                state.send_profile = value.clone();
+            }
+
+            state.outbox.rename_profile(&old, &value);
+            if let Err(e) = state.outbox.save() {
+                state.status = Some(format!("Error: could not save outbox: {}", e));
+            }
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::AddProfile => {
+            let name = state.profiles.unique_name("Profile");
+            state.profiles.profiles.push(config::Profile {
+                name,
+                settings: config::EmailSettings::default(),
+            });
+            state.editing_profile = state.profiles.profiles.len() - 1;
+            state.profile_name_buffer = None;
+            state.load_body_template();
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::DeleteProfile => {
+            if state.profiles.profiles.len() > 1 {
+                let removed = state.profiles.profiles.remove(state.editing_profile);
+                state.editing_profile =
+                    state.editing_profile.min(state.profiles.profiles.len() - 1);
+                state.profile_name_buffer = None;
+                state.load_body_template();
+
+                if state.profiles.default == removed.name {
+                    state.profiles.default = state.profiles.profiles[0].name.clone();
+                }
+                if state.send_profile == removed.name {
+                    state.send_profile = state.profiles.default.clone();
+                }
+                state.settings_changed = true;
+            }
+            Task::none()
+        }
+        Message::SetDefaultProfile => {
+            state.profiles.default = state.edited_profile().name.clone();
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::ToggleRecipient(email, selected) => {
+            state.selected_recipients.retain(|r| r != &email);
+            if selected {
+                state.selected_recipients.push(email);
+            }
+            Task::none()
+        }
+        Message::AddRecipient => {
+            state
+                .profiles
+                .address_book
+                .push(config::Recipient::default());
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::RecipientNameChanged(i, value) => {
+            if let Some(recipient) = state.profiles.address_book.get_mut(i) {
+                recipient.name = value;
+                state.settings_changed = true;
+            }
+            Task::none()
+        }
+        Message::RecipientEmailChanged(i, value) => {
+            if let Some(recipient) = state.profiles.address_book.get_mut(i) {
+                let old = std::mem::replace(&mut recipient.email, value.clone());
+
+                // The selection is keyed by address, so it follows the edit
+                if state.selected_recipients.contains(&old) {
+                    forget_recipient(state, &old);
+                    if !state.selected_recipients.contains(&value) {
+                        state.selected_recipients.push(value);
+                    }
+                }
+                state.settings_changed = true;
+            }
+            Task::none()
+        }
+        Message::RemoveRecipient(i) => {
+            if i < state.profiles.address_book.len() {
+                let removed = state.profiles.address_book.remove(i);
+                forget_recipient(state, &removed.email);
+                state.settings_changed = true;
+            }
+            Task::none()
+        }
+        Message::CompressionToggled(enabled) => {
+            state.profiles.compression.enabled = enabled;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::CompressionDpiChanged(value) => {
+            state.profiles.compression.dpi = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::CompressionQualityChanged(value) => {
+            state.profiles.compression.quality = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::ImagePageSizeChanged(page_size) => {
+            state.profiles.image_page_size = page_size;
             state.settings_changed = true;
             Task::none()
         }
+        Message::ImageFitChanged(fit) => {
+            state.profiles.image_fit = fit;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::RecipientModeChanged(mode) => {
+            state.edited_settings_mut().recipient_mode = mode;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::MasterPassphraseChanged(value) => {
+            state.master_passphrase = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::UnlockPassphraseChanged(value) => {
+            state.unlock_passphrase = value;
+            Task::none()
+        }
+        Message::Unlock => {
+            if state.unlocking {
+                return Task::none();
+            }
+            let Some(mut manager) = state.config_manager.clone() else {
+                return Task::none();
+            };
+
+            state.unlocking = true;
+            state.status = None;
+
+            // Deriving the key with Argon2 takes long enough to freeze the UI
+            manager.set_passphrase(Some(state.unlock_passphrase.clone()));
+            Task::perform(
+                async move {
+                    tokio::task::spawn_blocking(move || match manager.load_settings() {
+                        Ok(profiles) => Ok(profiles),
+                        Err(config::ConfigError::Decryption(_)) => {
+                            Err("wrong passphrase".to_string())
+                        }
+                        Err(e) => Err(e.to_string()),
+                    })
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|result| result)
+                },
+                Message::Unlocked,
+            )
+        }
+        Message::Unlocked(result) => {
+            state.unlocking = false;
+            match result {
+                Ok(profiles) => {
+                    let passphrase = std::mem::take(&mut state.unlock_passphrase);
+                    if let Some(manager) = state.config_manager.as_mut() {
+                        manager.set_passphrase(Some(passphrase.clone()));
+                    }
+
+                    state.profiles = profiles;
+                    state.send_profile = state.profiles.default.clone();
+                    state.master_passphrase = passphrase;
+                    state.locked = false;
+                    state.status = None;
+
+                    process_queue(state)
+                }
+                Err(e) => {
+                    state.status = Some(format!("Error: {}", e));
+                    Task::none()
+                }
+            }
+        }
+        Message::AuthMethodChanged(method) => {
+            state.edited_settings_mut().auth_method = method;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::OAuthProviderChanged(provider) => {
+            state.edited_settings_mut().oauth.set_provider(provider);
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::OAuthClientIdChanged(value) => {
+            state.edited_settings_mut().oauth.client_id = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::OAuthClientSecretChanged(value) => {
+            state.edited_settings_mut().oauth.client_secret = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::OAuthAuthUrlChanged(value) => {
+            state.edited_settings_mut().oauth.auth_url = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::OAuthTokenUrlChanged(value) => {
+            state.edited_settings_mut().oauth.token_url = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::OAuthScopeChanged(value) => {
+            state.edited_settings_mut().oauth.scope = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::OAuthSignIn => {
+            if state.signing_in {
+                return Task::none();
+            }
+
+            state.signing_in = true;
+            state.oauth_status = Some(Ok("Waiting for the browser...".to_string()));
+
+            let oauth_settings = state.edited_profile().settings.oauth.clone();
+            let profile = state.edited_profile().name.clone();
+            // Waits for the browser redirect, then exchanges the code over HTTP
+            Task::perform(
+                async move {
+                    tokio::task::spawn_blocking(move || {
+                        oauth::authorize(&oauth_settings).map_err(|e| e.to_string())
+                    })
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|result| result)
+                },
+                move |result| Message::OAuthSignedIn(profile.clone(), result),
+            )
+        }
+        Message::OAuthSignedIn(profile, result) => {
+            state.signing_in = false;
+
+            let Some(i) = state
+                .profiles
+                .profiles
+                .iter()
+                .position(|p| p.name == profile)
+            else {
+                return Task::none();
+            };
+
+            match result {
+                Ok(refresh_token) => {
+                    state.profiles.profiles[i].settings.oauth.refresh_token = refresh_token;
+                    state.oauth_status =
+                        Some(Ok("Signed in, save the settings to keep it".to_string()));
+                    state.settings_changed = true;
+                    Task::none()
+                }
+                Err(e) => {
+                    state.oauth_status = Some(Err(e));
+                    Task::none()
+                }
+            }
+        }
+        Message::OAuthSignOut => {
+            state.edited_settings_mut().oauth.refresh_token.clear();
+            state.oauth_status = None;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::TlsModeChanged(mode) => {
+            state.edited_settings_mut().tls_mode = mode;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::CaCertPathChanged(value) => {
+            state.edited_settings_mut().ca_cert_path = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::PinnedCertPathChanged(value) => {
+            state.edited_settings_mut().pinned_cert_path = value;
+            state.settings_changed = true;
+            Task::none()
+        }
+        Message::BrowseCaCert => Task::perform(pick_certificate(), Message::CaCertPicked),
+        Message::BrowsePinnedCert => Task::perform(pick_certificate(), Message::PinnedCertPicked),
+        Message::CaCertPicked(path) => match path {
+            Some(path) => update(state, Message::CaCertPathChanged(path)),
+            None => Task::none(),
+        },
+        Message::PinnedCertPicked(path) => match path {
+            Some(path) => update(state, Message::PinnedCertPathChanged(path)),
+            None => Task::none(),
+        },
+        Message::TestConnection => {
+            if state.testing_connection {
+                return Task::none();
+            }
+
+            state.testing_connection = true;
+            state.connection_test = None;
+
+            let settings = state.edited_profile().settings.clone();
+            Task::perform(
+                async move {
+                    tokio::task::spawn_blocking(move || {
+                        email::test_connection(&settings).map_err(|e| e.to_string())
+                    })
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|result| result)
+                },
+                Message::ConnectionTested,
+            )
+        }
+        Message::ConnectionTested(result) => {
+            state.testing_connection = false;
+            state.connection_test = Some(result);
+            save_rotated_refresh_tokens(state)
+        }
         Message::SaveSettings => {
-            let settings = state.settings.clone();
+            let settings = state.profiles.clone();
+            let passphrase = Some(state.master_passphrase.clone());
+
+            if let Some(manager) = state.config_manager.as_mut() {
+                manager.set_passphrase(passphrase.clone());
+            }
+            let manager = state.config_manager.clone();
+
             Task::perform(
                 async move {
-                    if let Ok(manager) = config::ConfigManager::new() {
+                    let manager = match manager {
+                        Some(manager) => Ok(manager),
+                        None => config::ConfigManager::new().map(|mut manager| {
+                            manager.set_passphrase(passphrase);
+                            manager
+                        }),
+                    };
+
+                    if let Ok(manager) = manager {
                         manager.save_settings(&settings).map_err(|e| e.to_string())
                     } else {
                         Err("Failed to create config manager".to_string())
@@ -211,30 +1136,544 @@ fn update(state: &mut AppState, message: Message) -> Task<Message> {
             }
             Task::none()
         }
+        Message::RefreshTokensSaved(result) => {
+            if let Err(e) = result {
+                state.status = Some(format!("Error saving renewed OAuth2 sign-in: {}", e));
+            }
+            Task::none()
+        }
+    }
+}
+
+/// Asks for a PEM certificate file.
+async fn pick_certificate() -> Option<String> {
+    rfd::FileDialog::new()
+        .add_filter("Certificate", &["pem", "crt", "cer"])
+        .set_title("Select certificate")
+        .pick_file()
+        .map(|p| p.to_string_lossy().into_owned())
+}
+
+/// Queues `files` for the profile and recipients picked in the main view,
+/// split into as many emails as needed, and starts sending. `subject`
+/// replaces the profile's subject template if given, and the rendered
+/// subject is tagged with `folder` if given.
+///
+/// `sources` has the hashes of the selected files that prepared
+/// attachments were made from, see [`queue::SendJob::sources`].
+fn enqueue_files(
+    state: &mut AppState,
+    files: Vec<(PathBuf, String)>,
+    sources: HashMap<PathBuf, Vec<String>>,
+    subject: Option<String>,
+    folder: Option<String>,
+) -> Task<Message> {
+    let Some(profile) = state.send_profile().cloned() else {
+        state.status = Some("Error: no profile configured".to_string());
+        return Task::none();
+    };
+
+    let batches = match email::split_into_batches(files, &profile.settings) {
+        Ok(batches) => batches,
+        Err(e) => {
+            state.status = Some(format!("Error: {}", e));
+            return Task::none();
+        }
+    };
+
+    let recipients: Vec<String> = state
+        .profiles
+        .address_book
+        .iter()
+        .filter(|r| state.selected_recipients.contains(&r.email))
+        .map(|r| r.email.clone())
+        .collect();
+    let groups = email::recipient_groups(&profile.settings, &recipients);
+
+    for batch in batches {
+        for group in &groups {
+            let batch_sources = sources
+                .iter()
+                .filter(|(path, _)| batch.iter().any(|(p, _)| p == *path))
+                .map(|(path, hashes)| (path.clone(), hashes.clone()))
+                .collect();
+            state.outbox.enqueue(
+                batch.clone(),
+                Some(profile.name.clone()),
+                group.clone(),
+                subject.clone(),
+                folder.clone(),
+                batch_sources,
+            );
+        }
+    }
+
+    if let Err(e) = state.outbox.save() {
+        state.status = Some(format!("Error saving queue: {}", e));
+    }
+
+    process_queue(state)
+}
+
+/// Starts sending the oldest due job in the outbox, if nothing is in flight.
+fn process_queue(state: &mut AppState) -> Task<Message> {
+    if state.sending || state.locked {
+        return Task::none();
+    }
+
+    let Some(job) = state.outbox.next_due(queue::now()) else {
+        return Task::none();
+    };
+
+    let id = job.id;
+    let files = job.files.clone();
+    let subject = job.subject.clone();
+    let folder = job.folder.clone();
+    let routing = state.profiles.folder_routing.clone();
+    let sources = job.sources.clone();
+    let profile = match &job.profile {
+        Some(name) => state.profiles.get(name),
+        None => state.profiles.default_profile(),
+    };
+
+    let Some(settings) = profile.map(|p| p.settings.clone()) else {
+        let name = job.profile.clone().unwrap_or_default();
+        return Task::done(Message::Sent(
+            id,
+            Err(email::SendFailure {
+                error: format!("profile '{}' no longer exists", name),
+                permanent: true,
+            }),
+        ));
+    };
+
+    state.sending = true;
+    state.sending_job = Some(id);
+    state.status = Some(format!(
+        "Sending email {} of {}...",
+        state.sent_emails + 1,
+        state.sent_emails + state.outbox.pending_count()
+    ));
+
+    let recipients = if job.recipients.is_empty() {
+        vec![settings.to_email.clone()]
+    } else {
+        job.recipients.clone()
+    };
+
+    let (task, handle) = Task::stream(iced::stream::channel(16, move |mut output| async move {
+        let result = email::send_pdfs(
+            files,
+            &settings,
+            &recipients,
+            subject.as_deref(),
+            folder.as_deref().map(|folder| (&routing, folder)),
+            |progress| {
+                // Progress that doesn't fit in the channel is simply skipped
+                let _ = output.try_send(Message::SendProgress(id, progress));
+            },
+        )
+        .await
+        .map(|mut sent| {
+            for file in &mut sent.files {
+                // Only attachments made from other files get a list of sources
+                if let Some(hashes) = sources.get(&file.path)
+                    && *hashes != [file.sha256.as_str()]
+                {
+                    file.sources = hashes.clone();
+                }
+            }
+            sent
+        })
+        .map_err(email::SendFailure::from);
+
+        let _ = output.send(Message::Sent(id, result)).await;
+    }))
+    .abortable();
+
+    state.send_handle = Some(handle);
+
+    task
+}
+
+/// Puts refresh tokens the OAuth2 provider rotated into the profiles and
+/// the saved settings, as the old ones may no longer work.
+fn save_rotated_refresh_tokens(state: &mut AppState) -> Task<Message> {
+    let rotated = oauth::take_rotated_refresh_tokens();
+    if rotated.is_empty() {
+        return Task::none();
     }
+    state.profiles.replace_refresh_tokens(&rotated);
+    let Some(manager) = state.config_manager.clone() else {
+        return Task::none();
+    };
+
+    Task::perform(
+        async move {
+            tokio::task::spawn_blocking(move || {
+                manager
+                    .save_refresh_tokens(&rotated)
+                    .map_err(|e| e.to_string())
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result)
+        },
+        Message::RefreshTokensSaved,
+    )
 }
 
-fn add_files_to_state(state: &mut AppState, files: Vec<PathBuf>) -> usize {
+/// Unselects `email` unless another address book entry still has it.
+fn forget_recipient(state: &mut AppState, email: &str) {
+    if !state.profiles.address_book.iter().any(|r| r.email == email) {
+        state.selected_recipients.retain(|r| r != email);
+    }
+}
+
+/// Adds the PDFs among `files` to the selection and starts hashing them.
+/// Images are converted into a single PDF, added once it is written.
+fn add_files_to_state(state: &mut AppState, files: Vec<PathBuf>) -> Task<Message> {
+    let images: Vec<PathBuf> = files.iter().filter(|p| pdf::is_image(p)).cloned().collect();
+    let conversion = if images.is_empty() {
+        Task::none()
+    } else {
+        convert_images(
+            images,
+            state.profiles.image_page_size,
+            state.profiles.image_fit,
+        )
+    };
+
     let mut new_files: Vec<PathBuf> = files
         .into_iter()
-        .filter(|p| p.extension().map(|e| e.to_string_lossy().to_lowercase()) == Some("pdf".into()))
+        .filter(|p| is_pdf(p))
         .collect();
 
-    new_files.retain(|f| !state.selected_files.iter().any(|e| e == f));
+    new_files.retain(|f| !state.files.iter().any(|e| &e.path == f));
 
-    let file_count = new_files.len();
+    let mut tasks = vec![conversion];
+    for path in new_files {
+        let id = state.next_file_id;
+        state.next_file_id += 1;
 
-    for f in &new_files {
-        state.file_names.push(default_display_name(f));
+        let render = !state
+            .previews
+            .get(&path)
+            .is_some_and(|p| p.is_current(&path));
+        tasks.push(load_file_details(id, path.clone(), render));
+
+        state.files.push(app::SelectedFile {
+            id,
+            name: default_display_name(&path),
+            size: std::fs::metadata(&path).map(|m| m.len()).ok(),
+            hash: None,
+            check: None,
+            page_ranges: String::new(),
+            folder: None,
+            path,
+        });
     }
 
-    state.selected_files.extend(new_files);
+    Task::batch(tasks)
+}
+
+/// Hashes and checks the file added as `id` off the UI thread, and renders
+/// its preview if `render` is set.
+fn load_file_details(id: u64, path: PathBuf, render: bool) -> Task<Message> {
+    let hashed = path.clone();
+    let hash = Task::perform(
+        async move {
+            tokio::task::spawn_blocking(move || history::file_sha256(&hashed))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|hash| hash.map_err(|e| e.to_string()))
+        },
+        move |result| Message::FileHashed(id, result),
+    );
+
+    let checked = path.clone();
+    let check = Task::perform(
+        async move {
+            tokio::task::spawn_blocking(move || pdf::validate(&checked))
+                .await
+                .unwrap_or_else(|e| pdf::Validation::Corrupt(e.to_string()))
+        },
+        move |validation| Message::FileValidated(id, validation),
+    );
+
+    if !render {
+        return Task::batch([hash, check]);
+    }
 
-    file_count
+    let preview = Task::perform(
+        async move {
+            tokio::task::spawn_blocking(move || {
+                // Taken before rendering so that changes made meanwhile are
+                // picked up next time
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                (modified, pdf::preview(&path).map_err(|e| e.to_string()))
+            })
+            .await
+            .unwrap_or_else(|e| (None, Err(e.to_string())))
+        },
+        move |(modified, preview)| Message::FilePreviewed(id, modified, preview),
+    );
+
+    Task::batch([hash, check, preview])
+}
+
+/// Selected files sent to the same destination folder.
+struct SendGroup {
+    /// Folder to put in the subject, `None` for none.
+    folder: Option<String>,
+    files: Vec<(PathBuf, String)>,
+    page_ranges: Vec<Vec<pdf::PageRange>>,
+    /// SHA-256 of each file.
+    hashes: Vec<String>,
 }
 
-fn commit_edit(state: &mut AppState, index: usize) {
-    if index < state.file_names.len() {
+/// Splits the selected files, along with their parsed `page_ranges`, by
+/// destination folder in list order. With folders going in file names,
+/// the names are tagged and everything stays in one group.
+fn group_by_folder(state: &AppState, page_ranges: Vec<Vec<pdf::PageRange>>) -> Vec<SendGroup> {
+    let routing = &state.profiles.folder_routing;
+    let mut groups: Vec<SendGroup> = Vec::new();
+
+    for (file, ranges) in state.files.iter().zip(page_ranges) {
+        let folder = file.folder.clone().or_else(|| state.send_folder.clone());
+
+        let (folder, name) = match (folder, routing.target) {
+            (Some(folder), FolderTarget::FileName) => {
+                (None, routing.apply_to_name(&folder, &file.name))
+            }
+            (folder, _) => (folder, file.name.clone()),
+        };
+
+        let index = match groups.iter().position(|g| g.folder == folder) {
+            Some(index) => index,
+            None => {
+                groups.push(SendGroup {
+                    folder,
+                    files: Vec::new(),
+                    page_ranges: Vec::new(),
+                    hashes: Vec::new(),
+                });
+                groups.len() - 1
+            }
+        };
+        groups[index].files.push((file.path.clone(), name));
+        groups[index].page_ranges.push(ranges);
+        groups[index]
+            .hashes
+            .push(file.hash.clone().unwrap_or_default());
+    }
+
+    groups
+}
+
+/// Changes made to the selected files before they are queued.
+struct Preparation {
+    /// Pages to send of each file, empty for all.
+    page_ranges: Vec<Vec<pdf::PageRange>>,
+    /// SHA-256 of each file, logged for the attachments made from it.
+    hashes: Vec<String>,
+    merge: bool,
+    /// DPI, JPEG quality and message size limit to optimize files that are
+    /// too large to send with, `None` to send them as they are.
+    compression: Option<(u32, u8, u64)>,
+}
+
+impl Preparation {
+    fn is_needed(&self) -> bool {
+        self.merge || self.compression.is_some() || self.page_ranges.iter().any(|r| !r.is_empty())
+    }
+}
+
+/// Runs [`prepare_attachments`] off the UI thread, then queues the result
+/// with `subject` and `folder`.
+fn prepare_files(
+    files: Vec<(PathBuf, String)>,
+    preparation: Preparation,
+    subject: Option<String>,
+    folder: Option<String>,
+) -> Task<Message> {
+    Task::perform(
+        async move {
+            tokio::task::spawn_blocking(move || prepare_attachments(files, preparation))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|result| result.map_err(|e| e.to_string()))
+        },
+        move |result| Message::FilesPrepared(subject.clone(), folder.clone(), result),
+    )
+}
+
+/// Extracts the page ranges picked for each file into separate PDFs,
+/// optimizes the ones too large to send, then concatenates everything into
+/// one PDF named after the first attachment if requested.
+///
+/// Intermediate PDFs are deleted, as is everything written if a step fails.
+fn prepare_attachments(
+    files: Vec<(PathBuf, String)>,
+    preparation: Preparation,
+) -> Result<app::PreparedFiles, pdf::PdfError> {
+    let mut generated = Vec::new();
+    let result = prepare_into(files, preparation, &mut generated);
+
+    for path in generated {
+        let attached = result
+            .as_ref()
+            .is_ok_and(|prepared| prepared.attachments.iter().any(|(a, _)| *a == path));
+        if !attached {
+            pdf::remove_output(&path);
+        }
+    }
+
+    result
+}
+
+/// Does the work of [`prepare_attachments`], adding every PDF it writes to
+/// `generated`.
+fn prepare_into(
+    files: Vec<(PathBuf, String)>,
+    preparation: Preparation,
+    generated: &mut Vec<PathBuf>,
+) -> Result<app::PreparedFiles, pdf::PdfError> {
+    let sources: Vec<PathBuf> = files.iter().map(|(path, _)| path.clone()).collect();
+    let mut optimized = Vec::new();
+    let mut shrink = |path: PathBuf,
+                      name: &str,
+                      source: &Path,
+                      generated: &mut Vec<PathBuf>|
+     -> Result<PathBuf, pdf::PdfError> {
+        let Some((dpi, quality, limit)) = preparation.compression else {
+            return Ok(path);
+        };
+
+        let before = std::fs::metadata(&path)?.len();
+        if email::attachment_fits(before, limit) {
+            return Ok(path);
+        }
+
+        let output = pdf::output_path(name)?;
+        generated.push(output.clone());
+        pdf::optimize(&path, &output, dpi, quality)?;
+        let after = std::fs::metadata(&output)?.len();
+
+        optimized.push(pdf::Optimized {
+            source: source.to_path_buf(),
+            name: name.to_string(),
+            before,
+            after,
+        });
+
+        Ok(if after < before { output } else { path })
+    };
+
+    // Attachments along with the indices of the selected files they come from
+    let mut attachments: Vec<(PathBuf, String, Vec<usize>)> = Vec::new();
+
+    for (i, ((path, name), ranges)) in files
+        .into_iter()
+        .zip(preparation.page_ranges)
+        .enumerate()
+    {
+        if ranges.is_empty() {
+            let output = shrink(path.clone(), &name, &path, generated)?;
+            attachments.push((output, name, vec![i]));
+            continue;
+        }
+
+        for range in ranges {
+            let part_name = pdf::range_display_name(&name, range);
+            let output = pdf::output_path(&part_name)?;
+            generated.push(output.clone());
+            pdf::extract_pages(&path, &name, range, &output)?;
+
+            let output = shrink(output, &part_name, &path, generated)?;
+            attachments.push((output, part_name, vec![i]));
+        }
+    }
+
+    if preparation.merge && attachments.len() > 1 {
+        let name = attachments[0].1.clone();
+        let mut merged_sources: Vec<usize> = attachments
+            .iter()
+            .flat_map(|(_, _, sources)| sources.iter().copied())
+            .collect();
+        merged_sources.dedup();
+        let parts: Vec<(PathBuf, String)> = attachments
+            .into_iter()
+            .map(|(path, name, _)| (path, name))
+            .collect();
+
+        let output = pdf::output_path(&name)?;
+        generated.push(output.clone());
+        pdf::merge(&parts, &output)?;
+
+        let output = shrink(output, &name, &sources[merged_sources[0]], generated)?;
+        attachments = vec![(output, name, merged_sources)];
+    }
+
+    let mut source_hashes = HashMap::new();
+    for (path, _, indices) in &attachments {
+        let hashes = indices
+            .iter()
+            .map(|&i| preparation.hashes[i].clone())
+            .collect();
+        source_hashes.insert(path.clone(), hashes);
+    }
+
+    Ok(app::PreparedFiles {
+        attachments: attachments
+            .into_iter()
+            .map(|(path, name, _)| (path, name))
+            .collect(),
+        optimized,
+        sources: source_hashes,
+    })
+}
+
+/// Deletes the PDF at `path` if it was converted from images and no email
+/// waiting in the outbox attaches it.
+fn remove_conversion(state: &AppState, path: &Path) {
+    if pdf::is_output(path) && !state.outbox.needs(path) {
+        pdf::remove_output(path);
+    }
+}
+
+/// Takes images converted to PDF off the list once the outbox has deleted
+/// them along with the email they were in.
+fn forget_removed_conversions(state: &mut AppState) {
+    state
+        .files
+        .retain(|f| !pdf::is_output(&f.path) || f.path.exists());
+}
+
+/// Writes `images` into a temporary PDF named after the first one.
+fn convert_images(images: Vec<PathBuf>, page_size: PageSize, fit: ImageFit) -> Task<Message> {
+    Task::perform(
+        async move {
+            let stem = images[0]
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "images".to_string());
+
+            tokio::task::spawn_blocking(move || {
+                let output = pdf::output_path(&format!("{stem}.pdf"))?;
+                pdf::images_to_pdf(&images, &output, page_size, fit)?;
+                Ok::<_, pdf::PdfError>(output)
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result.map_err(|e| e.to_string()))
+        },
+        Message::ImagesConverted,
+    )
+}
+
+fn commit_edit(state: &mut AppState, id: u64) {
+    if let Some(file) = state.files.iter_mut().find(|f| f.id == id) {
         let trimmed = state.editing_buffer.trim();
 
         if !trimmed.is_empty() {
@@ -246,13 +1685,17 @@ fn commit_edit(state: &mut AppState, index: usize) {
                 final_name.push_str(".pdf");
             }
 
-            state.file_names[index] = final_name;
+            file.name = final_name;
         }
     }
 
     state.editing_buffer.clear();
 }
 
+fn is_pdf(p: &Path) -> bool {
+    p.extension().map(|e| e.to_string_lossy().to_lowercase()) == Some("pdf".into())
+}
+
 fn default_display_name(p: &Path) -> String {
     p.file_name()
         .and_then(|n| n.to_str())
@@ -260,16 +1703,170 @@ fn default_display_name(p: &Path) -> String {
         .to_string()
 }
 
-fn subscription(_state: &AppState) -> Subscription<Message> {
-    iced::event::listen_with(|event, _status, _id| {
+fn subscription(state: &AppState) -> Subscription<Message> {
+    let file_drops = iced::event::listen_with(|event, _status, _id| {
         if let Event::Window(window::Event::FileDropped(path)) = event {
             Some(Message::FilesDropped(vec![path]))
         } else {
             None
         }
+    });
+
+    let mut subscriptions = vec![file_drops];
+
+    // Poll for jobs whose retry backoff has elapsed
+    if state.outbox.pending_count() > 0 && !state.sending {
+        subscriptions.push(iced::time::every(Duration::from_secs(5)).map(|_| Message::Tick));
+    }
+
+    if state.watching && !state.profiles.watch_folders.is_empty() {
+        let folders = state.profiles.watch_folders.clone();
+        subscriptions.push(Subscription::run_with_id(
+            folders.clone(),
+            watch_folders(folders),
+        ));
+    }
+
+    Subscription::batch(subscriptions)
+}
+
+/// Stream of the PDFs that appear in `folders`, once they are checked.
+fn watch_folders(folders: Vec<String>) -> impl iced::futures::Stream<Item = Message> {
+    iced::stream::channel(16, move |mut output| async move {
+        let (tx, mut rx) = iced::futures::channel::mpsc::unbounded();
+        let folders: Vec<PathBuf> = folders.iter().map(PathBuf::from).collect();
+
+        // Dropping the watcher at the end stops watching
+        // Files are checked here, on the watcher's thread
+        let _watcher = match watch::watch(&folders, move |path| {
+            let validation = pdf::validate(&path);
+            let _ = tx.unbounded_send((path, validation));
+            true
+        }) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                let _ = output.send(Message::WatchFailed(e.to_string())).await;
+                return;
+            }
+        };
+
+        while let Some((path, validation)) = rx.next().await {
+            let message = if validation.is_valid() {
+                Message::WatchedFileReady(path)
+            } else {
+                Message::WatchedFileInvalid(path, validation)
+            };
+            let _ = output.send(message).await;
+        }
     })
 }
 
 fn view(state: &AppState) -> iced::Element<'_, Message> {
     app::view(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// State with one selected file per entry of `folders`, each going to
+    /// its folder or the one picked for the send.
+    fn state_with_files(folders: &[Option<&str>], send_folder: Option<&str>) -> AppState {
+        let mut state = AppState {
+            send_folder: send_folder.map(str::to_string),
+            ..AppState::default()
+        };
+        for (i, folder) in folders.iter().enumerate() {
+            state.files.push(app::SelectedFile {
+                id: i as u64,
+                path: PathBuf::from(format!("{i}.pdf")),
+                name: format!("{i}.pdf"),
+                size: None,
+                hash: Some(format!("hash{i}")),
+                check: None,
+                page_ranges: String::new(),
+                folder: folder.map(str::to_string),
+            });
+        }
+        state
+    }
+
+    fn names(group: &SendGroup) -> Vec<&str> {
+        group.files.iter().map(|(_, name)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn groups_files_by_folder_in_list_order() {
+        let state = state_with_files(
+            &[None, Some("Physics"), None, Some("Biology")],
+            Some("Biology"),
+        );
+
+        let groups = group_by_folder(&state, vec![vec![], vec![(1, 2)], vec![], vec![]]);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].folder.as_deref(), Some("Biology"));
+        assert_eq!(names(&groups[0]), ["0.pdf", "2.pdf", "3.pdf"]);
+        assert_eq!(groups[0].hashes, ["hash0", "hash2", "hash3"]);
+        assert_eq!(groups[1].folder.as_deref(), Some("Physics"));
+        assert_eq!(names(&groups[1]), ["1.pdf"]);
+        assert_eq!(groups[1].page_ranges, [vec![(1, 2)]]);
+    }
+
+    #[test]
+    fn folders_in_file_names_keep_one_group() {
+        let mut state = state_with_files(&[None, Some("Physics"), None], None);
+        state.profiles.folder_routing.target = FolderTarget::FileName;
+
+        let groups = group_by_folder(&state, vec![vec![]; 3]);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].folder, None);
+        assert_eq!(names(&groups[0]), ["0.pdf", "[Physics] 1.pdf", "2.pdf"]);
+    }
+
+    fn file_names(state: &AppState) -> Vec<&str> {
+        state.files.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    #[test]
+    fn moving_past_either_end_does_nothing() {
+        let mut state = state_with_files(&[None; 3], None);
+
+        let _ = update(&mut state, Message::MoveUp(0));
+        let _ = update(&mut state, Message::MoveDown(2));
+        assert_eq!(file_names(&state), ["0.pdf", "1.pdf", "2.pdf"]);
+
+        let _ = update(&mut state, Message::MoveDown(0));
+        assert_eq!(file_names(&state), ["1.pdf", "0.pdf", "2.pdf"]);
+        let _ = update(&mut state, Message::MoveUp(2));
+        let _ = update(&mut state, Message::MoveUp(2));
+        let _ = update(&mut state, Message::MoveUp(2));
+        assert_eq!(file_names(&state), ["2.pdf", "1.pdf", "0.pdf"]);
+    }
+
+    #[test]
+    fn edits_follow_their_file_after_reordering() {
+        let mut state = state_with_files(&[None; 3], None);
+
+        // Start renaming the first file, then move it to the end
+        let _ = update(&mut state, Message::EditName(0));
+        let _ = update(&mut state, Message::MoveDown(0));
+        let _ = update(&mut state, Message::MoveDown(0));
+        let _ = update(&mut state, Message::NameChanged("Renamed".to_string()));
+        let _ = update(&mut state, Message::CommitEdit);
+
+        let _ = update(&mut state, Message::PageRangesChanged(0, "1-2".to_string()));
+        let _ = update(
+            &mut state,
+            Message::FileFolderSelected(1, Some("Physics".to_string())),
+        );
+        let _ = update(&mut state, Message::Remove(2));
+
+        assert_eq!(file_names(&state), ["1.pdf", "Renamed.pdf"]);
+        assert_eq!(state.files[0].folder.as_deref(), Some("Physics"));
+        assert_eq!(state.files[0].page_ranges, "");
+        assert_eq!(state.files[1].folder, None);
+        assert_eq!(state.files[1].page_ranges, "1-2");
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use base64::{Engine as _, engine::general_purpose};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use url::Url;
+
+use crate::config::OAuthSettings;
+
+/// How long to wait for the browser to come back to the loopback address.
+const AUTHORIZE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Access tokens are refreshed this long before they actually expire.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum OAuthError {
+    #[error("OAuth2 is not fully configured")]
+    IncompleteSettings,
+    #[error("not signed in, use \"Sign in\" in the settings")]
+    NotSignedIn,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid URL: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("token request failed: {0}")]
+    Http(String),
+    #[error("token request rejected: {0}")]
+    Rejected(String),
+    #[error("authorization failed: {0}")]
+    Denied(String),
+    #[error("authorization timed out")]
+    Timeout,
+    #[error("token response has no refresh token")]
+    NoRefreshToken,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct TokenErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+// Access tokens by refresh token, so consecutive emails reuse one token
+static ACCESS_TOKENS: LazyLock<Mutex<HashMap<String, (String, Instant)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Refresh tokens issued in place of the saved ones, by the token they
+// replace, until the settings are updated
+static ROTATED_REFRESH_TOKENS: LazyLock<Mutex<HashMap<String, String>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Runs the authorization code flow with PKCE through the browser and a
+/// loopback redirect, returning the refresh token.
+pub fn authorize(settings: &OAuthSettings) -> Result<String, OAuthError> {
+    if settings.client_id.is_empty()
+        || settings.auth_url.is_empty()
+        || settings.token_url.is_empty()
+    {
+        return Err(OAuthError::IncompleteSettings);
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let redirect_uri = format!("http://127.0.0.1:{}", listener.local_addr()?.port());
+
+    let verifier = random_token();
+    let challenge = general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let state = random_token();
+
+    let mut auth_url = Url::parse(&settings.auth_url)?;
+    auth_url
+        .query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &settings.client_id)
+        .append_pair("redirect_uri", &redirect_uri)
+        .append_pair("scope", &settings.scope)
+        .append_pair("state", &state)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256")
+        // Google only returns a refresh token with these
+        .append_pair("access_type", "offline")
+        .append_pair("prompt", "consent");
+
+    if let Err(e) = open::that(auth_url.as_str()) {
+        eprintln!("Could not open browser ({e}), visit this URL to sign in: {auth_url}");
+    }
+
+    let params = wait_for_redirect(&listener, &state, AUTHORIZE_TIMEOUT)?;
+
+    if let Some(error) = params.get("error") {
+        return Err(OAuthError::Denied(error.clone()));
+    }
+    let code = params
+        .get("code")
+        .ok_or_else(|| OAuthError::Denied("no authorization code returned".to_string()))?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("client_id", settings.client_id.as_str()),
+        ("code_verifier", verifier.as_str()),
+    ];
+    if !settings.client_secret.is_empty() {
+        form.push(("client_secret", settings.client_secret.as_str()));
+    }
+
+    let token = request_token(&settings.token_url, &form)?;
+    let refresh_token = token.refresh_token.ok_or(OAuthError::NoRefreshToken)?;
+    cache_access_token(&refresh_token, token.access_token, token.expires_in);
+
+    Ok(refresh_token)
+}
+
+/// Returns a valid access token for the signed in account, refreshing it
+/// if the cached one is missing or about to expire.
+///
+/// A refresh token rotated by the server replaces the saved one in later
+/// requests, and is returned by [`take_rotated_refresh_tokens`] to be saved.
+pub fn access_token(settings: &OAuthSettings) -> Result<String, OAuthError> {
+    if settings.refresh_token.is_empty() {
+        return Err(OAuthError::NotSignedIn);
+    }
+    if settings.client_id.is_empty() || settings.token_url.is_empty() {
+        return Err(OAuthError::IncompleteSettings);
+    }
+
+    let rotated = ROTATED_REFRESH_TOKENS
+        .lock()
+        .ok()
+        .and_then(|rotated| rotated.get(&settings.refresh_token).cloned());
+    let refresh_token = rotated.unwrap_or_else(|| settings.refresh_token.clone());
+
+    if let Some((token, expires_at)) = ACCESS_TOKENS
+        .lock()
+        .ok()
+        .and_then(|cache| cache.get(&refresh_token).cloned())
+        && Instant::now() + EXPIRY_MARGIN < expires_at
+    {
+        return Ok(token);
+    }
+
+    let mut form = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token.as_str()),
+        ("client_id", settings.client_id.as_str()),
+    ];
+    if !settings.client_secret.is_empty() {
+        form.push(("client_secret", settings.client_secret.as_str()));
+    }
+
+    let token = request_token(&settings.token_url, &form)?;
+
+    // Some providers revoke the old refresh token once they issue a new one
+    let refresh_token = match token.refresh_token {
+        Some(new) if new != refresh_token => {
+            if let Ok(mut rotated) = ROTATED_REFRESH_TOKENS.lock() {
+                for replacement in rotated.values_mut().filter(|t| **t == refresh_token) {
+                    *replacement = new.clone();
+                }
+                rotated.insert(settings.refresh_token.clone(), new.clone());
+            }
+            new
+        }
+        _ => refresh_token,
+    };
+    cache_access_token(&refresh_token, token.access_token.clone(), token.expires_in);
+
+    Ok(token.access_token)
+}
+
+/// Returns the refresh tokens rotated since the last call, as pairs of the
+/// saved token and the one to save in its place.
+pub fn take_rotated_refresh_tokens() -> Vec<(String, String)> {
+    ROTATED_REFRESH_TOKENS
+        .lock()
+        .map(|mut rotated| rotated.drain().collect())
+        .unwrap_or_default()
+}
+
+fn cache_access_token(refresh_token: &str, access_token: String, expires_in: Option<u64>) {
+    // Assume the common one hour lifetime if the server doesn't say
+    let expires_at = Instant::now() + Duration::from_secs(expires_in.unwrap_or(3600));
+
+    if let Ok(mut cache) = ACCESS_TOKENS.lock() {
+        cache.insert(refresh_token.to_string(), (access_token, expires_at));
+    }
+}
+
+fn request_token(token_url: &str, form: &[(&str, &str)]) -> Result<TokenResponse, OAuthError> {
+    let tls = native_tls::TlsConnector::new().map_err(|e| OAuthError::Http(e.to_string()))?;
+    let agent = ureq::AgentBuilder::new()
+        .tls_connector(Arc::new(tls))
+        .timeout(Duration::from_secs(30))
+        .build();
+
+    match agent.post(token_url).send_form(form) {
+        Ok(response) => response
+            .into_json::<TokenResponse>()
+            .map_err(|e| OAuthError::Http(e.to_string())),
+        Err(ureq::Error::Status(code, response)) => {
+            // e.g. invalid_grant once the user revoked access
+            Err(match response.into_json::<TokenErrorResponse>() {
+                Ok(TokenErrorResponse {
+                    error,
+                    error_description: Some(description),
+                }) => OAuthError::Rejected(format!("{error}: {description}")),
+                Ok(TokenErrorResponse { error, .. }) => OAuthError::Rejected(error),
+                Err(_) => OAuthError::Http(format!("HTTP {code}")),
+            })
+        }
+        Err(e) => Err(OAuthError::Http(e.to_string())),
+    }
+}
+
+/// Accepts connections on `listener` until one carries the redirect query
+/// for `state`, then answers it with a short page telling the user to
+/// return to the app. Anything else reaching the port is turned away.
+fn wait_for_redirect(
+    listener: &TcpListener,
+    state: &str,
+    timeout: Duration,
+) -> Result<HashMap<String, String>, OAuthError> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // A broken connection is not the browser coming back
+                if let Ok(Some(params)) = handle_redirect(stream, state) {
+                    return Ok(params);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(OAuthError::Timeout);
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn handle_redirect(
+    mut stream: TcpStream,
+    state: &str,
+) -> Result<Option<HashMap<String, String>>, OAuthError> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    // e.g. "GET /?code=...&state=... HTTP/1.1"
+    let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let url = Url::parse(&format!("http://127.0.0.1{target}"))?;
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+    // Browsers also ask for things like /favicon.ico, and other programs
+    // may find the port
+    let answered = params.contains_key("code") || params.contains_key("error");
+    if !answered || params.get("state").map(String::as_str) != Some(state) {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(None);
+    }
+
+    let body =
+        "<html><body><p>Signed in to Send to Goodnotes. You can close this tab.</p></body></html>";
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+
+    Ok(Some(params))
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::mpsc;
+
+    /// Serves each of `responses` as (status line, JSON body) to one token
+    /// request in turn, sending the request bodies back.
+    fn token_server(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut request = vec![0; length];
+                reader.read_exact(&mut request).unwrap();
+                sender.send(String::from_utf8(request).unwrap()).unwrap();
+
+                write!(
+                    &stream,
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+
+        (url, receiver)
+    }
+
+    /// Settings signed in with `refresh_token`, which each test keeps
+    /// unique as the token cache is shared.
+    fn settings(token_url: String, refresh_token: &str) -> OAuthSettings {
+        OAuthSettings {
+            client_id: "client".to_string(),
+            token_url,
+            refresh_token: refresh_token.to_string(),
+            ..OAuthSettings::default()
+        }
+    }
+
+    #[test]
+    fn refreshes_and_reuses_the_access_token() {
+        let (url, requests) = token_server(vec![(
+            "200 OK",
+            r#"{"access_token":"access-1","expires_in":3600}"#,
+        )]);
+        let settings = settings(url, "refresh-cached");
+
+        assert_eq!(access_token(&settings).unwrap(), "access-1");
+        let request = requests.recv().unwrap();
+        assert!(request.contains("grant_type=refresh_token"));
+        assert!(request.contains("refresh_token=refresh-cached"));
+        assert!(request.contains("client_id=client"));
+
+        // Served from the cache, the server only answers once
+        assert_eq!(access_token(&settings).unwrap(), "access-1");
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn refreshes_an_expiring_access_token() {
+        let (url, requests) = token_server(vec![
+            ("200 OK", r#"{"access_token":"access-1","expires_in":30}"#),
+            ("200 OK", r#"{"access_token":"access-2","expires_in":3600}"#),
+        ]);
+        let settings = settings(url, "refresh-expiring");
+
+        assert_eq!(access_token(&settings).unwrap(), "access-1");
+        assert_eq!(access_token(&settings).unwrap(), "access-2");
+        assert_eq!(requests.iter().take(2).count(), 2);
+    }
+
+    #[test]
+    fn keeps_a_rotated_refresh_token() {
+        let (url, requests) = token_server(vec![
+            (
+                "200 OK",
+                r#"{"access_token":"access-1","refresh_token":"refresh-new","expires_in":0}"#,
+            ),
+            ("200 OK", r#"{"access_token":"access-2","expires_in":3600}"#),
+        ]);
+        let settings = settings(url, "refresh-old");
+
+        assert_eq!(access_token(&settings).unwrap(), "access-1");
+        assert!(
+            requests
+                .recv()
+                .unwrap()
+                .contains("refresh_token=refresh-old")
+        );
+
+        // Settings that still hold the old token use the new one
+        assert_eq!(access_token(&settings).unwrap(), "access-2");
+        assert!(
+            requests
+                .recv()
+                .unwrap()
+                .contains("refresh_token=refresh-new")
+        );
+
+        let rotated = take_rotated_refresh_tokens();
+        assert!(rotated.contains(&("refresh-old".to_string(), "refresh-new".to_string())));
+    }
+
+    #[test]
+    fn reports_a_rejected_refresh_token() {
+        let (url, _requests) = token_server(vec![(
+            "400 Bad Request",
+            r#"{"error":"invalid_grant","error_description":"Token has been revoked."}"#,
+        )]);
+
+        match access_token(&settings(url, "refresh-revoked")) {
+            Err(OAuthError::Rejected(message)) => {
+                assert_eq!(message, "invalid_grant: Token has been revoked.")
+            }
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+    }
+
+    fn get(port: u16, target: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(stream, "GET {target} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    }
+
+    #[test]
+    fn ignores_stray_requests_on_the_redirect_port() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let browser = std::thread::spawn(move || {
+            // Dropped without sending anything
+            drop(TcpStream::connect(("127.0.0.1", port)).unwrap());
+
+            let strays = [
+                get(port, "/favicon.ico"),
+                get(port, "/?code=stolen"),
+                get(port, "/?code=stolen&state=guess"),
+            ];
+            let redirect = get(port, "/?code=the-code&state=expected");
+            (strays, redirect)
+        });
+
+        let params = wait_for_redirect(&listener, "expected", Duration::from_secs(10)).unwrap();
+        assert_eq!(params.get("code").map(String::as_str), Some("the-code"));
+
+        let (strays, redirect) = browser.join().unwrap();
+        for response in strays {
+            assert!(response.starts_with("HTTP/1.1 404"));
+        }
+        assert!(redirect.starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn times_out_without_a_redirect() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let stray = std::thread::spawn(move || get(port, "/?code=stolen"));
+
+        assert!(matches!(
+            wait_for_redirect(&listener, "expected", Duration::from_millis(500)),
+            Err(OAuthError::Timeout)
+        ));
+        assert!(stray.join().unwrap().starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,1235 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, GrayImage, ImageDecoder, ImageFormat, ImageReader, RgbImage};
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat, dictionary};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+
+use crate::config::{ImageFit, PageSize};
+
+/// Extensions of the images that can be turned into a PDF.
+pub const IMAGE_EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
+
+/// Bytes at the start of a file searched for the `%PDF-` header.
+const HEADER_WINDOW: usize = 1024;
+
+/// Bytes at the end of a file searched for `startxref` and `%%EOF`.
+const TRAILER_WINDOW: usize = 2048;
+
+/// Height in pixels that first pages are rendered at for previews.
+const THUMBNAIL_HEIGHT: f32 = 128.0;
+
+/// Points per pixel when the page takes the size of the image, i.e. 96 DPI.
+const POINTS_PER_PIXEL: f32 = 72.0 / 96.0;
+
+static OUTPUT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Error)]
+pub enum PdfError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("could not read image: {0}")]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Pdf(#[from] lopdf::Error),
+    #[error("could not render page: {0}")]
+    Render(String),
+    #[error("no files given")]
+    NoFiles,
+    #[error("{0} has no pages")]
+    NoPages(String),
+    #[error("invalid page range: {0}")]
+    InvalidPageRange(String),
+    #[error("pages {first}-{last} are out of range, {name} has {pages} pages")]
+    PageOutOfRange {
+        name: String,
+        first: u32,
+        last: u32,
+        pages: u32,
+    },
+}
+
+/// An attachment that was optimized because it was too large to send.
+#[derive(Debug, Clone)]
+pub struct Optimized {
+    /// Selected file the attachment was made from.
+    pub source: PathBuf,
+    /// Display name of the attachment.
+    pub name: String,
+    pub before: u64,
+    pub after: u64,
+}
+
+/// Whether a file can be sent as a PDF.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Validation {
+    Valid,
+    /// Protected by a password or encrypted with restrictions.
+    Encrypted,
+    /// Not a PDF, or damaged; holds the reason.
+    Corrupt(String),
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        *self == Validation::Valid
+    }
+}
+
+impl std::fmt::Display for Validation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Validation::Valid => write!(f, "valid PDF"),
+            Validation::Encrypted => write!(f, "encrypted, remove the password to send it"),
+            Validation::Corrupt(reason) => write!(f, "not a valid PDF: {}", reason),
+        }
+    }
+}
+
+/// Thumbnail of the first page of a PDF along with its size.
+#[derive(Debug, Clone)]
+pub struct Preview {
+    /// PNG of the first page, [`THUMBNAIL_HEIGHT`] pixels high.
+    pub thumbnail: Vec<u8>,
+    pub pages: usize,
+    /// Size of the file in bytes.
+    pub size: u64,
+}
+
+/// Inclusive range of page numbers, starting at 1.
+pub type PageRange = (u32, u32);
+
+pub fn is_image(p: &Path) -> bool {
+    p.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.as_str()))
+}
+
+/// Path for a generated PDF called `file_name`, in a new directory under the
+/// system temp directory so that names never clash.
+pub fn output_path(file_name: &str) -> Result<PathBuf, PdfError> {
+    let dir = output_root().join(format!(
+        "{}-{}",
+        std::process::id(),
+        OUTPUT_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir)?;
+
+    Ok(dir.join(file_name.replace(['/', '\\'], "-")))
+}
+
+/// Whether `path` was made by [`output_path`].
+pub fn is_output(path: &Path) -> bool {
+    path.parent()
+        .is_some_and(|dir| dir.parent() == Some(output_root().as_path()))
+}
+
+/// Deletes a PDF made by [`output_path`] along with its directory. Paths
+/// outside the directory generated PDFs are written to are left alone.
+pub fn remove_output(path: &Path) {
+    if is_output(path)
+        && let Some(dir) = path.parent()
+    {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+fn output_root() -> PathBuf {
+    std::env::temp_dir().join("send-to-goodnotes")
+}
+
+/// Renders the first page of the PDF at `path` in software.
+pub fn preview(path: &Path) -> Result<Preview, PdfError> {
+    let data = fs::read(path)?;
+    let size = data.len() as u64;
+
+    let pdf = hayro::Pdf::new(Arc::new(data)).map_err(|e| PdfError::Render(format!("{:?}", e)))?;
+    let pages = pdf.pages();
+    let Some(page) = pages.first() else {
+        return Err(PdfError::NoPages(path.display().to_string()));
+    };
+
+    let (_, height) = page.render_dimensions();
+    let scale = THUMBNAIL_HEIGHT / height.max(1.0);
+    let settings = hayro::RenderSettings {
+        x_scale: scale,
+        y_scale: scale,
+        ..Default::default()
+    };
+    let pixmap = hayro::render(page, &hayro::InterpreterSettings::default(), &settings);
+
+    Ok(Preview {
+        thumbnail: pixmap.take_png(),
+        pages: pages.len(),
+        size,
+    })
+}
+
+/// Checks the header, cross-reference section and trailer of the file at
+/// `path`, then that it parses and has pages.
+pub fn validate(path: &Path) -> Validation {
+    match fs::read(path) {
+        Ok(data) => validate_bytes(&data),
+        Err(e) => Validation::Corrupt(e.to_string()),
+    }
+}
+
+fn validate_bytes(data: &[u8]) -> Validation {
+    let corrupt = |reason: &str| Validation::Corrupt(reason.to_string());
+
+    if find(&data[..data.len().min(HEADER_WINDOW)], b"%PDF-").is_none() {
+        return corrupt("missing %PDF- header");
+    }
+
+    let tail_start = data.len().saturating_sub(TRAILER_WINDOW);
+    let tail = &data[tail_start..];
+    if rfind(tail, b"%%EOF").is_none() {
+        return corrupt("missing %%EOF marker, the file may be truncated");
+    }
+    let Some(startxref) = rfind(tail, b"startxref") else {
+        return corrupt("missing startxref");
+    };
+
+    let Some(offset) = std::str::from_utf8(&tail[startxref + b"startxref".len()..])
+        .ok()
+        .and_then(|s| s.split_whitespace().next())
+        .and_then(|s| s.parse::<usize>().ok())
+    else {
+        return corrupt("invalid startxref offset");
+    };
+    let end = tail_start + startxref;
+    if offset >= end {
+        return Validation::Corrupt(format!(
+            "cross-reference offset {} is past the end of the file",
+            offset
+        ));
+    }
+
+    // Either a cross-reference table followed by the trailer, or a
+    // cross-reference stream whose dictionary is the trailer
+    let section = data[offset..end].trim_ascii_start();
+    if !section.starts_with(b"xref") && !starts_with_object(section) {
+        return Validation::Corrupt(format!("no cross-reference section at offset {}", offset));
+    }
+    if find(section, b"/Encrypt").is_some() {
+        return Validation::Encrypted;
+    }
+
+    match Document::load_mem(data) {
+        Ok(doc) if doc.trailer.has(b"Encrypt") => Validation::Encrypted,
+        Ok(doc) if doc.get_pages().is_empty() => corrupt("no pages"),
+        Ok(_) => Validation::Valid,
+        Err(e) => Validation::Corrupt(e.to_string()),
+    }
+}
+
+/// Whether `data` starts with an indirect object header such as `12 0 obj`.
+fn starts_with_object(data: &[u8]) -> bool {
+    let mut parts = data
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|p| !p.is_empty());
+    let is_number = |p: Option<&[u8]>| p.is_some_and(|p| p.iter().all(u8::is_ascii_digit));
+
+    is_number(parts.next())
+        && is_number(parts.next())
+        && parts.next().is_some_and(|p| p.starts_with(b"obj"))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+/// Writes a PDF to `output` with one page per image, in order.
+///
+/// Pages are turned to landscape for landscape images, after applying the
+/// EXIF orientation. JPEGs are embedded as they are, other images are
+/// flattened onto white and stored losslessly.
+pub fn images_to_pdf(
+    images: &[PathBuf],
+    output: &Path,
+    page_size: PageSize,
+    fit: ImageFit,
+) -> Result<(), PdfError> {
+    if images.is_empty() {
+        return Err(PdfError::NoFiles);
+    }
+
+    let mut doc = Document::with_version("1.5");
+    let pages_id = doc.new_object_id();
+    let mut kids: Vec<Object> = Vec::new();
+
+    for path in images {
+        let (xobject, width, height, orientation) = image_xobject(path)?;
+        let image_id = doc.add_object(xobject);
+
+        // Size of the image as it is meant to be seen
+        let (width, height) = match orientation {
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH => (height as f32, width as f32),
+            _ => (width as f32, height as f32),
+        };
+
+        let (page_width, page_height) = match page_size.dimensions() {
+            Some((w, h)) if width > height => (h, w),
+            Some(dimensions) => dimensions,
+            None => (width * POINTS_PER_PIXEL, height * POINTS_PER_PIXEL),
+        };
+
+        let scale_x = page_width / width;
+        let scale_y = page_height / height;
+        let scale = match fit {
+            ImageFit::Fit => scale_x.min(scale_y),
+            ImageFit::Fill => scale_x.max(scale_y),
+        };
+        let (w, h) = (width * scale, height * scale);
+        let (x, y) = ((page_width - w) / 2.0, (page_height - h) / 2.0);
+
+        // Turn the image within its unit square, then scale it onto the page
+        let [a, b, c, d, e, f] = orientation_matrix(orientation);
+        let content = Content {
+            operations: vec![
+                Operation::new("q", vec![]),
+                Operation::new(
+                    "cm",
+                    vec![
+                        (w * a).into(),
+                        (h * b).into(),
+                        (w * c).into(),
+                        (h * d).into(),
+                        (w * e + x).into(),
+                        (h * f + y).into(),
+                    ],
+                ),
+                Operation::new("Do", vec!["Im0".into()]),
+                Operation::new("Q", vec![]),
+            ],
+        };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode()?));
+
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "MediaBox" => vec![0.0f32.into(), 0.0f32.into(), page_width.into(), page_height.into()],
+            "Resources" => dictionary! {
+                "XObject" => dictionary! { "Im0" => image_id },
+            },
+        });
+        kids.push(page_id.into());
+    }
+
+    let count = kids.len() as i64;
+    doc.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids,
+            "Count" => count,
+        }),
+    );
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    doc.trailer.set("Root", catalog_id);
+
+    doc.compress();
+    doc.save(output)?;
+
+    Ok(())
+}
+
+/// Image XObject for the image at `path`, with its width and height in
+/// pixels as stored and the EXIF orientation it is to be shown in.
+fn image_xobject(path: &Path) -> Result<(Stream, u32, u32, Orientation), PdfError> {
+    let data = fs::read(path)?;
+    let format = image::guess_format(&data)?;
+    let mut decoder = ImageReader::with_format(Cursor::new(&data), format).into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let image = DynamicImage::from_decoder(decoder)?;
+    let (width, height) = (image.width(), image.height());
+
+    // The decoder turns CMYK and YCCK JPEGs into RGB as well, so the color
+    // space of the embedded data comes from the file itself
+    let passthrough = match format {
+        ImageFormat::Jpeg => match jpeg_components(&data) {
+            Some(3) => Some("DeviceRGB"),
+            Some(1) => Some("DeviceGray"),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    let stream = match passthrough {
+        Some(color_space) => Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width as i64,
+                "Height" => height as i64,
+                "ColorSpace" => color_space,
+                "BitsPerComponent" => 8,
+                "Filter" => "DCTDecode",
+            },
+            data,
+        ),
+        None => Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width as i64,
+                "Height" => height as i64,
+                "ColorSpace" => "DeviceRGB",
+                "BitsPerComponent" => 8,
+            },
+            flatten_onto_white(&image),
+        ),
+    };
+
+    Ok((stream, width, height, orientation))
+}
+
+/// Number of color components of the 8-bit JPEG `data`, read from its
+/// start of frame marker. `None` for other precisions or if there is none.
+fn jpeg_components(data: &[u8]) -> Option<u8> {
+    let mut pos = 2;
+
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        pos += 2;
+
+        match marker {
+            // Fill byte before the actual marker
+            0xFF => pos -= 1,
+            // Markers without a segment
+            0x01 | 0xD0..=0xD7 => {}
+            // Start of frame, except DHT, JPG and DAC which share the range
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let precision = *data.get(pos + 2)?;
+                let components = *data.get(pos + 7)?;
+                return (precision == 8).then_some(components);
+            }
+            // Start of scan or end of image before any frame
+            0xDA | 0xD9 => return None,
+            _ => {
+                let length = u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]);
+                pos += usize::from(length);
+            }
+        }
+    }
+}
+
+/// Matrix `[a b c d e f]` that maps the unit square an image is drawn in
+/// onto itself so that the image appears in `orientation`. Unlike rotating
+/// the pixels, this keeps JPEGs embedded as they are.
+fn orientation_matrix(orientation: Orientation) -> [f32; 6] {
+    match orientation {
+        Orientation::NoTransforms => [1.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+        Orientation::Rotate90 => [0.0, -1.0, 1.0, 0.0, 0.0, 1.0],
+        Orientation::Rotate180 => [-1.0, 0.0, 0.0, -1.0, 1.0, 1.0],
+        Orientation::Rotate270 => [0.0, 1.0, -1.0, 0.0, 1.0, 0.0],
+        Orientation::FlipHorizontal => [-1.0, 0.0, 0.0, 1.0, 1.0, 0.0],
+        Orientation::FlipVertical => [1.0, 0.0, 0.0, -1.0, 0.0, 1.0],
+        Orientation::Rotate90FlipH => [0.0, -1.0, -1.0, 0.0, 1.0, 1.0],
+        Orientation::Rotate270FlipH => [0.0, 1.0, 1.0, 0.0, 0.0, 0.0],
+    }
+}
+
+/// RGB pixels of `image`, with transparent areas blended onto white.
+fn flatten_onto_white(image: &DynamicImage) -> Vec<u8> {
+    if !image.color().has_alpha() {
+        return image.to_rgb8().into_raw();
+    }
+
+    let rgba = image.to_rgba8();
+    let mut rgb = Vec::with_capacity(rgba.len() / 4 * 3);
+    for pixel in rgba.pixels() {
+        let alpha = u16::from(pixel[3]);
+        for &channel in &pixel.0[..3] {
+            rgb.push(((u16::from(channel) * alpha + 255 * (255 - alpha)) / 255) as u8);
+        }
+    }
+
+    rgb
+}
+
+/// Parses a list of page ranges such as `1-20, 45-60, 70`. An empty list
+/// means the whole document.
+pub fn parse_page_ranges(s: &str) -> Result<Vec<PageRange>, PdfError> {
+    let mut ranges = Vec::new();
+
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let invalid = || PdfError::InvalidPageRange(part.to_string());
+        let parse = |n: &str| n.trim().parse::<u32>().ok().filter(|n| *n > 0);
+
+        let range = match part.split_once('-') {
+            Some((first, last)) => (
+                parse(first).ok_or_else(invalid)?,
+                parse(last).ok_or_else(invalid)?,
+            ),
+            None => {
+                let page = parse(part).ok_or_else(invalid)?;
+                (page, page)
+            }
+        };
+        if range.0 > range.1 {
+            return Err(invalid());
+        }
+
+        ranges.push(range);
+    }
+
+    Ok(ranges)
+}
+
+/// Display name for the pages `range` taken out of `display_name`, e.g.
+/// `Textbook (pp. 1-20).pdf`.
+pub fn range_display_name(display_name: &str, range: PageRange) -> String {
+    let base = strip_pdf_extension(display_name);
+    if range.0 == range.1 {
+        format!("{} (p. {}).pdf", base, range.0)
+    } else {
+        format!("{} (pp. {}-{}).pdf", base, range.0, range.1)
+    }
+}
+
+/// Writes the pages `range` of the PDF at `input` to `output`.
+///
+/// `display_name` is only used in the error if the document is shorter than
+/// the range.
+pub fn extract_pages(
+    input: &Path,
+    display_name: &str,
+    range: PageRange,
+    output: &Path,
+) -> Result<(), PdfError> {
+    let mut doc = Document::load(input)?;
+
+    let pages = doc.get_pages().len() as u32;
+    if range.1 > pages {
+        return Err(PdfError::PageOutOfRange {
+            name: display_name.to_string(),
+            first: range.0,
+            last: range.1,
+            pages,
+        });
+    }
+
+    let removed: Vec<u32> = (1..=pages)
+        .filter(|n| *n < range.0 || *n > range.1)
+        .collect();
+    doc.delete_pages(&removed);
+    doc.prune_objects();
+    doc.save(output)?;
+
+    Ok(())
+}
+
+/// Page attributes a page can inherit from its ancestors in the page tree.
+const INHERITABLE_KEYS: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+/// Concatenates the PDFs in `inputs` into `output`, in order, with an
+/// outline entry titled after each input's display name pointing at its
+/// first page.
+pub fn merge(inputs: &[(PathBuf, String)], output: &Path) -> Result<(), PdfError> {
+    if inputs.is_empty() {
+        return Err(PdfError::NoFiles);
+    }
+
+    let mut merged = Document::with_version("1.5");
+    let pages_id = merged.new_object_id();
+    let mut kids: Vec<Object> = Vec::new();
+    let mut bookmarks: Vec<(String, ObjectId)> = Vec::new();
+
+    for (path, display_name) in inputs {
+        let mut doc = Document::load(path)?;
+        doc.renumber_objects_with(merged.max_id + 1);
+        merged.max_id = doc.max_id;
+
+        let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+        let Some(&first_page) = pages.first() else {
+            return Err(PdfError::NoPages(display_name.clone()));
+        };
+        bookmarks.push((strip_pdf_extension(display_name), first_page));
+
+        for page_id in pages {
+            let mut page = doc.get_dictionary(page_id)?.clone();
+            for key in INHERITABLE_KEYS {
+                if page.get(key).is_err()
+                    && let Some(value) = inherited(&doc, &page, key)
+                {
+                    page.set(key, value);
+                }
+            }
+            page.set("Parent", pages_id);
+
+            doc.objects.insert(page_id, Object::Dictionary(page));
+            kids.push(page_id.into());
+        }
+
+        // The page tree and catalog of each input are replaced by the merged ones
+        merged
+            .objects
+            .extend(doc.objects.into_iter().filter(|(_, object)| {
+                !matches!(
+                    object.type_name(),
+                    Ok(b"Catalog") | Ok(b"Pages") | Ok(b"Outlines")
+                )
+            }));
+    }
+
+    let count = kids.len() as i64;
+    merged.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids,
+            "Count" => count,
+        }),
+    );
+
+    let outlines_id = merged.new_object_id();
+    let item_ids: Vec<ObjectId> = bookmarks.iter().map(|_| merged.new_object_id()).collect();
+    for (i, (title, page_id)) in bookmarks.into_iter().enumerate() {
+        let mut item = dictionary! {
+            "Title" => text_string(&title),
+            "Parent" => outlines_id,
+            "Dest" => vec![page_id.into(), "Fit".into()],
+        };
+        if i > 0 {
+            item.set("Prev", item_ids[i - 1]);
+        }
+        if let Some(&next) = item_ids.get(i + 1) {
+            item.set("Next", next);
+        }
+
+        merged.objects.insert(item_ids[i], Object::Dictionary(item));
+    }
+    merged.objects.insert(
+        outlines_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Outlines",
+            "First" => item_ids[0],
+            "Last" => item_ids[item_ids.len() - 1],
+            "Count" => item_ids.len() as i64,
+        }),
+    );
+
+    let catalog_id = merged.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+        "Outlines" => outlines_id,
+        "PageMode" => "UseOutlines",
+    });
+    merged.trailer.set("Root", catalog_id);
+
+    // Drops what only the old catalogs referred to, e.g. their outline items
+    merged.prune_objects();
+    merged.save(output)?;
+
+    Ok(())
+}
+
+/// Value of `key` set on the closest ancestor of `page` in the page tree.
+fn inherited(doc: &Document, page: &Dictionary, key: &[u8]) -> Option<Object> {
+    let mut parent = page.get(b"Parent").and_then(Object::as_reference).ok();
+    while let Some(id) = parent {
+        let node = doc.get_dictionary(id).ok()?;
+        if let Ok(value) = node.get(key) {
+            return Some(value.clone());
+        }
+        parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+
+    None
+}
+
+/// PDF text string for `s`, UTF-16 encoded unless it is plain ASCII.
+fn text_string(s: &str) -> Object {
+    if s.is_ascii() {
+        return Object::string_literal(s);
+    }
+
+    let mut bytes = vec![0xFE, 0xFF];
+    for unit in s.encode_utf16() {
+        bytes.extend_from_slice(&unit.to_be_bytes());
+    }
+
+    Object::String(bytes, StringFormat::Hexadecimal)
+}
+
+/// `name` without a trailing `.pdf`, in any case.
+pub fn strip_pdf_extension(name: &str) -> String {
+    // `get` rather than slicing, as the last 4 bytes may split a character
+    let split = name.len().saturating_sub(4);
+    match name.get(split..) {
+        Some(extension) if extension.eq_ignore_ascii_case(".pdf") => name[..split].to_string(),
+        _ => name.to_string(),
+    }
+}
+
+/// Writes a smaller copy of the PDF at `input` to `output`.
+///
+/// Images are re-encoded as JPEG at `quality`, and downsampled so that they
+/// have at most `dpi` pixels per inch when stretched over the largest page.
+/// Images are only replaced if that makes them smaller. Document metadata
+/// and objects nothing refers to are dropped.
+pub fn optimize(input: &Path, output: &Path, dpi: u32, quality: u8) -> Result<(), PdfError> {
+    let mut doc = Document::load(input)?;
+
+    let max_pixels = (largest_page_side(&doc) / 72.0 * dpi as f32).ceil() as u32;
+
+    doc.trailer.remove(b"Info");
+    if let Ok(catalog) = doc.catalog_mut() {
+        catalog.remove(b"Metadata");
+        catalog.remove(b"PieceInfo");
+    }
+
+    for object in doc.objects.values_mut() {
+        if let Object::Stream(stream) = object
+            && stream.dict.get(b"Subtype").and_then(Object::as_name).ok() == Some(b"Image")
+            && let Some(smaller) = recompress_image(stream, max_pixels, quality)
+        {
+            *stream = smaller;
+        }
+    }
+
+    doc.prune_objects();
+    doc.compress();
+    doc.save(output)?;
+
+    Ok(())
+}
+
+/// Longest side of the largest page in points, US Letter if unknown.
+fn largest_page_side(doc: &Document) -> f32 {
+    doc.get_pages()
+        .values()
+        .filter_map(|&id| {
+            let page = doc.get_dictionary(id).ok()?;
+            let media_box = match page.get(b"MediaBox") {
+                Ok(value) => value.clone(),
+                Err(_) => inherited(doc, page, b"MediaBox")?,
+            };
+            let values: Vec<f32> = media_box
+                .as_array()
+                .ok()?
+                .iter()
+                .filter_map(|v| v.as_float().ok())
+                .collect();
+
+            match values[..] {
+                [x0, y0, x1, y1] => Some((x1 - x0).abs().max((y1 - y0).abs())),
+                _ => None,
+            }
+        })
+        .reduce(f32::max)
+        .unwrap_or(792.0)
+}
+
+/// The image in `stream` as a smaller JPEG, or `None` if it can't be
+/// decoded or wouldn't get smaller.
+///
+/// Only plain `DeviceRGB` and `DeviceGray` images are touched, as the JPEG
+/// is written in one of these. ICC-based, indexed, CMYK and other color
+/// spaces, and images with a `Decode` array, are kept as they are.
+fn recompress_image(stream: &Stream, max_pixels: u32, quality: u8) -> Option<Stream> {
+    let dict = &stream.dict;
+    if dict
+        .get(b"ImageMask")
+        .and_then(Object::as_bool)
+        .unwrap_or(false)
+        || dict.get(b"BitsPerComponent").and_then(Object::as_i64).ok() != Some(8)
+        || dict.get(b"DecodeParms").is_ok()
+        || dict.get(b"Decode").is_ok()
+    {
+        return None;
+    }
+
+    let width = u32::try_from(dict.get(b"Width").and_then(Object::as_i64).ok()?).ok()?;
+    let height = u32::try_from(dict.get(b"Height").and_then(Object::as_i64).ok()?).ok()?;
+    let gray = match dict.get(b"ColorSpace").and_then(Object::as_name).ok()? {
+        b"DeviceRGB" => false,
+        b"DeviceGray" => true,
+        _ => return None,
+    };
+
+    let image = match dict.get(b"Filter").and_then(Object::as_name).ok() {
+        Some(b"DCTDecode") => {
+            let image =
+                image::load_from_memory_with_format(&stream.content, ImageFormat::Jpeg).ok()?;
+            // The JPEG must have the channels of the color space it is labelled with
+            if (image.color().channel_count() == 1) != gray {
+                return None;
+            }
+            image
+        }
+        filter @ (None | Some(b"FlateDecode")) => {
+            let data = match filter {
+                Some(_) => stream.decompressed_content().ok()?,
+                None => stream.content.clone(),
+            };
+
+            if gray {
+                DynamicImage::ImageLuma8(GrayImage::from_raw(width, height, data)?)
+            } else {
+                DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, data)?)
+            }
+        }
+        _ => return None,
+    };
+
+    let image = if image.width().max(image.height()) > max_pixels {
+        image.resize(max_pixels, max_pixels, FilterType::Triangle)
+    } else {
+        image
+    };
+
+    let image = if gray {
+        DynamicImage::ImageLuma8(image.to_luma8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+
+    let mut encoded = Vec::new();
+    image
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, quality))
+        .ok()?;
+    if encoded.len() >= stream.content.len() {
+        return None;
+    }
+
+    let mut dict = dict.clone();
+    dict.set("Width", image.width() as i64);
+    dict.set("Height", image.height() as i64);
+    dict.set("ColorSpace", if gray { "DeviceGray" } else { "DeviceRGB" });
+    dict.set("Filter", "DCTDecode");
+
+    Some(Stream::new(dict, encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ColorType, ImageEncoder, Rgb};
+
+    #[test]
+    fn strip_pdf_extension_handles_any_case_and_non_ascii_names() {
+        assert_eq!(strip_pdf_extension("Notes.pdf"), "Notes");
+        assert_eq!(strip_pdf_extension("Notes.PDF"), "Notes");
+        assert_eq!(strip_pdf_extension("Notes"), "Notes");
+        assert_eq!(strip_pdf_extension("pdf"), "pdf");
+        assert_eq!(strip_pdf_extension("日本"), "日本");
+        assert_eq!(strip_pdf_extension("日本.pdf"), "日本");
+    }
+
+    #[test]
+    fn parses_pages_and_ranges() {
+        assert_eq!(
+            parse_page_ranges("1-20, 45-60,7").unwrap(),
+            [(1, 20), (45, 60), (7, 7)]
+        );
+        assert_eq!(parse_page_ranges(" 3 - 5 ,").unwrap(), [(3, 5)]);
+        assert!(parse_page_ranges("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_page_ranges() {
+        for input in ["0", "5-3", "a", "1-", "-4", "1-2-3", "2, x"] {
+            assert!(
+                matches!(parse_page_ranges(input), Err(PdfError::InvalidPageRange(_))),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn range_display_name_keeps_the_extension_last() {
+        assert_eq!(
+            range_display_name("Textbook.pdf", (1, 20)),
+            "Textbook (pp. 1-20).pdf"
+        );
+        assert_eq!(range_display_name("日本.PDF", (3, 3)), "日本 (p. 3).pdf");
+    }
+
+    /// A document with `pages` empty pages.
+    fn blank_document(pages: usize) -> Document {
+        document_with_widths(&vec![595; pages])
+    }
+
+    /// A document with an empty page of each of the given widths, so that
+    /// the pages can be told apart.
+    fn document_with_widths(widths: &[i64]) -> Document {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let kids: Vec<Object> = widths
+            .iter()
+            .map(|&width| {
+                doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "MediaBox" => vec![0.into(), 0.into(), width.into(), 842.into()],
+                })
+                .into()
+            })
+            .collect();
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Count" => widths.len() as i64,
+                "Kids" => kids,
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        doc
+    }
+
+    fn save(mut doc: Document) -> Vec<u8> {
+        let mut data = Vec::new();
+        doc.save_to(&mut data).unwrap();
+        data
+    }
+
+    /// A PDF with `pages` empty pages.
+    fn blank_pdf(pages: usize) -> Vec<u8> {
+        save(blank_document(pages))
+    }
+
+    #[test]
+    fn accepts_a_well_formed_pdf() {
+        assert_eq!(validate_bytes(&blank_pdf(2)), Validation::Valid);
+    }
+
+    #[test]
+    fn rejects_damaged_pdfs() {
+        let data = blank_pdf(1);
+
+        let not_pdf = b"PK\x03\x04 not a pdf".to_vec();
+        let truncated = data[..data.len() / 2].to_vec();
+        let mut bad_offset = data.clone();
+        let startxref = rfind(&bad_offset, b"startxref").unwrap();
+        bad_offset.truncate(startxref);
+        bad_offset.extend_from_slice(b"startxref\n999999\n%%EOF\n");
+
+        for data in [not_pdf, truncated, bad_offset, blank_pdf(0)] {
+            assert!(matches!(validate_bytes(&data), Validation::Corrupt(_)));
+        }
+    }
+
+    #[test]
+    fn detects_encrypted_pdfs() {
+        let mut doc = blank_document(1);
+        let encrypt_id = doc.add_object(dictionary! {
+            "Filter" => "Standard",
+            "V" => 2,
+            "R" => 3,
+        });
+        doc.trailer.set("Encrypt", encrypt_id);
+
+        assert_eq!(validate_bytes(&save(doc)), Validation::Encrypted);
+    }
+
+    /// Widths of the pages of `doc`, in order.
+    fn page_widths(doc: &Document) -> Vec<i64> {
+        doc.get_pages()
+            .into_values()
+            .map(|id| {
+                let page = doc.get_dictionary(id).unwrap();
+                page.get(b"MediaBox").unwrap().as_array().unwrap()[2]
+                    .as_i64()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn extract_pages_keeps_only_the_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("scan.pdf");
+        fs::write(&input, save(document_with_widths(&[100, 200, 300, 400]))).unwrap();
+        let output = dir.path().join("part.pdf");
+
+        extract_pages(&input, "scan.pdf", (2, 3), &output).unwrap();
+
+        let doc = Document::load(&output).unwrap();
+        assert_eq!(doc.get_pages().len(), 2);
+        assert_eq!(page_widths(&doc), [200, 300]);
+    }
+
+    #[test]
+    fn extract_pages_rejects_ranges_past_the_last_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("scan.pdf");
+        fs::write(&input, blank_pdf(3)).unwrap();
+        let output = dir.path().join("part.pdf");
+
+        match extract_pages(&input, "Scan.pdf", (2, 4), &output) {
+            Err(PdfError::PageOutOfRange {
+                name,
+                first,
+                last,
+                pages,
+            }) => assert_eq!((name.as_str(), first, last, pages), ("Scan.pdf", 2, 4, 3)),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn merge_keeps_page_order_with_an_outline_entry_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first.pdf");
+        let second = dir.path().join("second.pdf");
+        fs::write(&first, save(document_with_widths(&[100, 200]))).unwrap();
+        fs::write(&second, save(document_with_widths(&[300]))).unwrap();
+        let output = dir.path().join("merged.pdf");
+
+        merge(
+            &[
+                (first, "Chapter 1.pdf".to_string()),
+                (second, "第2章.pdf".to_string()),
+            ],
+            &output,
+        )
+        .unwrap();
+
+        let doc = Document::load(&output).unwrap();
+        assert_eq!(page_widths(&doc), [100, 200, 300]);
+
+        let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+        let outlines = doc
+            .get_dictionary(
+                doc.catalog()
+                    .unwrap()
+                    .get(b"Outlines")
+                    .unwrap()
+                    .as_reference()
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(outlines.get(b"Count").unwrap().as_i64().unwrap(), 2);
+
+        let mut entries = Vec::new();
+        let mut next = outlines.get(b"First").ok();
+        while let Some(id) = next {
+            let item = doc.get_dictionary(id.as_reference().unwrap()).unwrap();
+            let dest = item.get(b"Dest").unwrap().as_array().unwrap();
+            entries.push((
+                item.get(b"Title").unwrap().clone(),
+                dest[0].as_reference().unwrap(),
+            ));
+            next = item.get(b"Next").ok();
+        }
+        assert_eq!(
+            entries,
+            [
+                (text_string("Chapter 1"), pages[0]),
+                (text_string("第2章"), pages[2]),
+            ]
+        );
+    }
+
+    /// 3×2 image whose pixels all differ.
+    fn sample_image() -> RgbImage {
+        RgbImage::from_fn(3, 2, |x, y| Rgb([x as u8 * 80, y as u8 * 80, 0]))
+    }
+
+    #[test]
+    fn orientation_matrix_matches_rotating_the_pixels() {
+        let stored = sample_image();
+        let (width, height) = stored.dimensions();
+
+        for exif in 1..=8 {
+            let orientation = Orientation::from_exif(exif).unwrap();
+            let mut shown = DynamicImage::ImageRgb8(stored.clone());
+            shown.apply_orientation(orientation);
+            let shown = shown.to_rgb8();
+            let [a, b, c, d, e, f] = orientation_matrix(orientation);
+
+            for (x, y, pixel) in stored.enumerate_pixels() {
+                // Centre of the pixel in the unit square, y pointing up
+                let u = (x as f32 + 0.5) / width as f32;
+                let v = 1.0 - (y as f32 + 0.5) / height as f32;
+                let (u, v) = (a * u + c * v + e, b * u + d * v + f);
+
+                let shown_x = (u * shown.width() as f32) as u32;
+                let shown_y = ((1.0 - v) * shown.height() as f32) as u32;
+                assert_eq!(
+                    shown.get_pixel(shown_x, shown_y),
+                    pixel,
+                    "EXIF orientation {exif}, pixel ({x}, {y})"
+                );
+            }
+        }
+    }
+
+    /// Uncompressed 64×64 RGB image XObject in `color_space`.
+    fn raw_image(color_space: Object) -> Stream {
+        let image = RgbImage::from_fn(64, 64, |x, y| Rgb([x as u8 * 4, y as u8 * 4, 128]));
+        Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => 64,
+                "Height" => 64,
+                "ColorSpace" => color_space,
+                "BitsPerComponent" => 8,
+            },
+            image.into_raw(),
+        )
+    }
+
+    #[test]
+    fn recompresses_device_rgb_images() {
+        let stream = raw_image("DeviceRGB".into());
+        let recompressed = recompress_image(&stream, 32, 80).unwrap();
+
+        assert_eq!(
+            recompressed.dict.get(b"Width").unwrap().as_i64().unwrap(),
+            32
+        );
+        assert_eq!(
+            recompressed.dict.get(b"Filter").unwrap().as_name().unwrap(),
+            b"DCTDecode"
+        );
+        assert!(recompressed.content.len() < stream.content.len());
+    }
+
+    #[test]
+    fn keeps_images_in_other_color_spaces() {
+        let icc = Object::Array(vec!["ICCBased".into(), Object::Reference((7, 0))]);
+        assert!(recompress_image(&raw_image(icc), 32, 80).is_none());
+
+        let indexed = Object::Array(vec![
+            "Indexed".into(),
+            "DeviceRGB".into(),
+            255.into(),
+            Object::Reference((8, 0)),
+        ]);
+        assert!(recompress_image(&raw_image(indexed), 32, 80).is_none());
+
+        assert!(recompress_image(&raw_image("DeviceCMYK".into()), 32, 80).is_none());
+
+        let mut decoded = raw_image("DeviceRGB".into());
+        decoded.dict.set(
+            "Decode",
+            vec![1.into(), 0.into(), 1.into(), 0.into(), 1.into(), 0.into()],
+        );
+        assert!(recompress_image(&decoded, 32, 80).is_none());
+    }
+
+    /// Big-endian EXIF block holding only the orientation tag.
+    fn exif_orientation(orientation: u16) -> Vec<u8> {
+        let mut exif = b"MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1]);
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        exif
+    }
+
+    #[test]
+    fn rotated_jpeg_keeps_its_data_and_turns_the_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+
+        let image = RgbImage::from_pixel(40, 20, Rgb([200, 10, 10]));
+        let mut jpeg = Vec::new();
+        let mut encoder = JpegEncoder::new(&mut jpeg);
+        encoder.set_exif_metadata(exif_orientation(6)).unwrap();
+        encoder
+            .write_image(image.as_raw(), 40, 20, ColorType::Rgb8.into())
+            .unwrap();
+        fs::write(&path, &jpeg).unwrap();
+
+        assert_eq!(jpeg_components(&jpeg), Some(3));
+        let (xobject, width, height, orientation) = image_xobject(&path).unwrap();
+        assert_eq!((width, height), (40, 20));
+        assert_eq!(orientation, Orientation::Rotate90);
+        assert_eq!(xobject.content, jpeg);
+
+        // Stored landscape, shown portrait
+        let output = dir.path().join("photo.pdf");
+        images_to_pdf(&[path], &output, PageSize::Image, ImageFit::Fit).unwrap();
+
+        let doc = Document::load(&output).unwrap();
+        let page = doc.get_pages().into_values().next().unwrap();
+        let media_box: Vec<f32> = doc
+            .get_dictionary(page)
+            .unwrap()
+            .get(b"MediaBox")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|n| n.as_float().unwrap())
+            .collect();
+        assert_eq!(
+            media_box,
+            [0.0, 0.0, 20.0 * POINTS_PER_PIXEL, 40.0 * POINTS_PER_PIXEL]
+        );
+    }
+
+    #[test]
+    fn gray_jpegs_are_embedded_as_gray() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan.jpg");
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg)
+            .write_image(&[128; 8 * 8], 8, 8, ColorType::L8.into())
+            .unwrap();
+        fs::write(&path, &jpeg).unwrap();
+
+        assert_eq!(jpeg_components(&jpeg), Some(1));
+        let (xobject, ..) = image_xobject(&path).unwrap();
+        assert_eq!(xobject.content, jpeg);
+        assert_eq!(
+            xobject.dict.get(b"ColorSpace").unwrap().as_name().unwrap(),
+            b"DeviceGray"
+        );
+    }
+
+    #[test]
+    fn jpeg_components_come_from_the_frame_header() {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg)
+            .write_image(sample_image().as_raw(), 3, 2, ColorType::Rgb8.into())
+            .unwrap();
+
+        // Baseline frame header: length, precision, height, width, components
+        let sof = jpeg.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        assert_eq!(jpeg[sof + 4], 8);
+        assert_eq!(jpeg_components(&jpeg), Some(3));
+
+        // As written by CMYK scanners
+        let mut cmyk = jpeg.clone();
+        cmyk[sof + 9] = 4;
+        assert_eq!(jpeg_components(&cmyk), Some(4));
+
+        let mut twelve_bit = jpeg.clone();
+        twelve_bit[sof + 4] = 12;
+        assert_eq!(jpeg_components(&twelve_bit), None);
+
+        assert_eq!(jpeg_components(&jpeg[..sof]), None);
+        assert_eq!(jpeg_components(b"\xFF\xD8\xFF\xD9"), None);
+    }
+}
@@ -0,0 +1,407 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::ConfigError;
+use crate::pdf;
+
+/// Number of failed attempts after which a job stops being retried.
+pub const MAX_ATTEMPTS: u32 = 8;
+
+const BASE_BACKOFF_SECS: u64 = 30;
+const MAX_BACKOFF_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobState {
+    Pending,
+    Failed,
+    Sent,
+}
+
+/// A single email waiting in the outbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendJob {
+    pub id: u64,
+    pub files: Vec<(PathBuf, String)>,
+    /// Settings profile to send with, `None` uses the current settings.
+    pub profile: Option<String>,
+    /// Addresses of the email, empty sends to the profile's `to_email`.
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// Subject template to use instead of the profile's.
+    #[serde(default)]
+    pub subject: Option<String>,
+    /// Destination folder the rendered subject is tagged with, see
+    /// [`crate::config::FolderRouting`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    /// SHA-256 of the selected files each attachment is, or was made from by
+    /// splitting, merging or compressing them, by attachment path. Attachments
+    /// made from other files and images converted to PDF are temporary files,
+    /// deleted with the job.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sources: HashMap<PathBuf, Vec<String>>,
+    pub state: JobState,
+    pub attempts: u32,
+    /// Unix timestamp (seconds) before which the job is not retried.
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OutboxFile {
+    next_id: u64,
+    jobs: Vec<SendJob>,
+}
+
+/// Durable queue of emails, stored as `outbox.json` in the config directory.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    path: Option<PathBuf>,
+    next_id: u64,
+    jobs: Vec<SendJob>,
+}
+
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Outbox {
+    /// Loads the outbox at `path`, dropping jobs that were already sent.
+    pub fn load(path: PathBuf) -> Result<Self, ConfigError> {
+        let file = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            OutboxFile::default()
+        };
+
+        let mut outbox = Self {
+            path: Some(path),
+            next_id: file.next_id,
+            jobs: file.jobs,
+        };
+        outbox.jobs.retain(|j| j.state != JobState::Sent);
+
+        Ok(outbox)
+    }
+
+    /// Empty outbox stored at `path`, for when the file there can't be
+    /// loaded. That file is renamed to `outbox.json.broken` first so the next
+    /// save doesn't overwrite its jobs, and the new name is returned.
+    pub fn replace_unreadable(path: PathBuf) -> io::Result<(Self, Option<PathBuf>)> {
+        let broken = if path.exists() {
+            let broken = with_suffix(&path, ".broken");
+            fs::rename(&path, &broken)?;
+            Some(broken)
+        } else {
+            None
+        };
+
+        let outbox = Self {
+            path: Some(path),
+            ..Self::default()
+        };
+        Ok((outbox, broken))
+    }
+
+    /// Writes the outbox to a temporary file next to it and renames that over
+    /// the old one, so a crash while saving can't leave a truncated file.
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let file = OutboxFile {
+            next_id: self.next_id,
+            jobs: self.jobs.clone(),
+        };
+
+        let temp = with_suffix(path, ".tmp");
+        let mut out = File::create(&temp)?;
+        out.write_all(serde_json::to_string_pretty(&file)?.as_bytes())?;
+        out.sync_all()?;
+        fs::rename(&temp, path)?;
+
+        Ok(())
+    }
+
+    pub fn jobs(&self) -> &[SendJob] {
+        &self.jobs
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.jobs
+            .iter()
+            .filter(|j| j.state == JobState::Pending)
+            .count()
+    }
+
+    /// Adds a job that is due immediately and returns its id.
+    pub fn enqueue(
+        &mut self,
+        files: Vec<(PathBuf, String)>,
+        profile: Option<String>,
+        recipients: Vec<String>,
+        subject: Option<String>,
+        folder: Option<String>,
+        sources: HashMap<PathBuf, Vec<String>>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.jobs.push(SendJob {
+            id,
+            files,
+            profile,
+            recipients,
+            subject,
+            folder,
+            sources,
+            state: JobState::Pending,
+            attempts: 0,
+            next_attempt: 0,
+            last_error: None,
+        });
+
+        id
+    }
+
+    /// Returns the oldest pending job whose backoff has elapsed.
+    pub fn next_due(&self, now: u64) -> Option<&SendJob> {
+        self.jobs
+            .iter()
+            .find(|j| j.state == JobState::Pending && j.next_attempt <= now)
+    }
+
+    pub fn mark_sent(&mut self, id: u64) {
+        if let Some(job) = self.jobs.iter_mut().find(|j| j.id == id) {
+            job.state = JobState::Sent;
+            job.last_error = None;
+        }
+        self.remove_prepared_files(id);
+    }
+
+    /// Records a failed attempt and schedules the next one with exponential
+    /// backoff. Returns `false` once the job has given up, right away for
+    /// `permanent` errors that retrying can't fix.
+    pub fn mark_failed(&mut self, id: u64, error: String, permanent: bool, now: u64) -> bool {
+        let Some(job) = self.jobs.iter_mut().find(|j| j.id == id) else {
+            return false;
+        };
+
+        job.attempts += 1;
+        job.last_error = Some(error);
+
+        if permanent || job.attempts >= MAX_ATTEMPTS {
+            job.state = JobState::Failed;
+            return false;
+        }
+
+        let backoff = BASE_BACKOFF_SECS
+            .saturating_mul(1 << (job.attempts - 1))
+            .min(MAX_BACKOFF_SECS);
+        job.next_attempt = now + backoff;
+
+        true
+    }
+
+    /// Makes a failed or waiting job due again right away.
+    pub fn retry(&mut self, id: u64) {
+        if let Some(job) = self.jobs.iter_mut().find(|j| j.id == id)
+            && job.state != JobState::Sent
+        {
+            job.state = JobState::Pending;
+            job.attempts = 0;
+            job.next_attempt = 0;
+        }
+    }
+
+    /// Stops every pending job, keeping it in the outbox to be retried by hand.
+    pub fn cancel_pending(&mut self) {
+        for job in self
+            .jobs
+            .iter_mut()
+            .filter(|j| j.state == JobState::Pending)
+        {
+            job.state = JobState::Failed;
+            job.last_error = Some("cancelled".to_string());
+        }
+    }
+
+    /// Points jobs queued for profile `old` at its new name.
+    pub fn rename_profile(&mut self, old: &str, new: &str) {
+        for job in &mut self.jobs {
+            if job.profile.as_deref() == Some(old) {
+                job.profile = Some(new.to_string());
+            }
+        }
+    }
+
+    /// Whether an unsent job attaches the file at `path`.
+    pub fn needs(&self, path: &Path) -> bool {
+        self.jobs
+            .iter()
+            .any(|j| j.state != JobState::Sent && j.files.iter().any(|(p, _)| p == path))
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.remove_prepared_files(id);
+        self.jobs.retain(|j| j.id != id);
+    }
+
+    /// Deletes the prepared attachments of job `id` that no other unsent
+    /// job still needs.
+    fn remove_prepared_files(&self, id: u64) {
+        let Some(job) = self.jobs.iter().find(|j| j.id == id) else {
+            return;
+        };
+
+        for path in job.sources.keys() {
+            let needed = self.jobs.iter().any(|j| {
+                j.id != id && j.state != JobState::Sent && j.files.iter().any(|(p, _)| p == path)
+            });
+            if !needed {
+                pdf::remove_output(path);
+            }
+        }
+    }
+
+    pub fn clear_sent(&mut self) {
+        self.jobs.retain(|j| j.state != JobState::Sent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &std::path::Path) -> Vec<(PathBuf, String)> {
+        vec![(path.to_path_buf(), "part.pdf".to_string())]
+    }
+
+    fn job(outbox: &Outbox, id: u64) -> &SendJob {
+        outbox.jobs.iter().find(|j| j.id == id).unwrap()
+    }
+
+    #[test]
+    fn failed_jobs_back_off_exponentially_until_they_give_up() {
+        let mut outbox = Outbox::default();
+        let id = outbox.enqueue(vec![], None, vec![], None, None, HashMap::new());
+
+        let mut delays = Vec::new();
+        for _ in 0..MAX_ATTEMPTS - 1 {
+            assert!(outbox.mark_failed(id, "timeout".to_string(), false, 1000));
+            delays.push(job(&outbox, id).next_attempt - 1000);
+        }
+        assert_eq!(delays, [30, 60, 120, 240, 480, 960, 1920]);
+
+        assert!(outbox.next_due(1000).is_none());
+        assert_eq!(outbox.next_due(2920).map(|j| j.id), Some(id));
+
+        // The last attempt gives up
+        assert!(!outbox.mark_failed(id, "timeout".to_string(), false, 1000));
+        assert_eq!(job(&outbox, id).state, JobState::Failed);
+        assert!(outbox.next_due(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn permanent_failures_give_up_right_away_until_retried() {
+        let mut outbox = Outbox::default();
+        let id = outbox.enqueue(vec![], None, vec![], None, None, HashMap::new());
+
+        assert!(!outbox.mark_failed(id, "rejected".to_string(), true, 0));
+        assert_eq!(job(&outbox, id).state, JobState::Failed);
+        assert_eq!(job(&outbox, id).attempts, 1);
+
+        outbox.retry(id);
+        assert_eq!(job(&outbox, id).state, JobState::Pending);
+        assert_eq!(job(&outbox, id).attempts, 0);
+        assert_eq!(outbox.next_due(0).map(|j| j.id), Some(id));
+    }
+
+    #[test]
+    fn prepared_files_are_deleted_once_no_job_needs_them() {
+        let prepared = pdf::output_path("part.pdf").unwrap();
+        fs::write(&prepared, b"%PDF-1.5").unwrap();
+        let sources = HashMap::from([(prepared.clone(), vec!["abc".to_string()])]);
+
+        // The same attachment going to two recipients
+        let mut outbox = Outbox::default();
+        let first = outbox.enqueue(file(&prepared), None, vec![], None, None, sources.clone());
+        let second = outbox.enqueue(file(&prepared), None, vec![], None, None, sources);
+
+        outbox.mark_sent(first);
+        assert!(prepared.exists());
+
+        outbox.mark_failed(second, "rejected".to_string(), true, 0);
+        assert!(prepared.exists());
+
+        outbox.remove(second);
+        assert!(!prepared.exists());
+        assert!(!prepared.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn selected_files_are_never_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let selected = dir.path().join("notes.pdf");
+        fs::write(&selected, b"%PDF-1.5").unwrap();
+        let sources = HashMap::from([(selected.clone(), vec!["abc".to_string()])]);
+
+        let mut outbox = Outbox::default();
+        let id = outbox.enqueue(file(&selected), None, vec![], None, None, sources);
+        outbox.mark_sent(id);
+        outbox.remove(id);
+
+        assert!(selected.exists());
+    }
+
+    #[test]
+    fn saving_replaces_the_file_in_one_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.json");
+
+        let mut outbox = Outbox::load(path.clone()).unwrap();
+        let id = outbox.enqueue(vec![], None, vec![], None, None, HashMap::new());
+        outbox.save().unwrap();
+        outbox.enqueue(vec![], None, vec![], None, None, HashMap::new());
+        outbox.save().unwrap();
+
+        let loaded = Outbox::load(path).unwrap();
+        assert_eq!(loaded.jobs().len(), 2);
+        assert_eq!(loaded.jobs()[0].id, id);
+        // Only the outbox itself is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn unreadable_outboxes_are_kept_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.json");
+        fs::write(&path, "{ not json").unwrap();
+        assert!(Outbox::load(path.clone()).is_err());
+
+        let (mut outbox, broken) = Outbox::replace_unreadable(path.clone()).unwrap();
+        let broken = broken.unwrap();
+        assert_eq!(broken, dir.path().join("outbox.json.broken"));
+        assert_eq!(fs::read_to_string(&broken).unwrap(), "{ not json");
+
+        // The new outbox is still saved where the old one was
+        outbox.enqueue(vec![], None, vec![], None, None, HashMap::new());
+        outbox.save().unwrap();
+        assert_eq!(Outbox::load(path).unwrap().jobs().len(), 1);
+    }
+}
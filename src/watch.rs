@@ -0,0 +1,245 @@
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a new file must go without growing before it is considered
+/// fully written.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// How long to wait before handing over a file again after it could not be
+/// handled, doubled on each further failure up to `MAX_RETRY_DELAY`.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches `folders` for new or changed PDFs and calls `on_ready` with each
+/// one once it has stopped changing.
+///
+/// `on_ready` returns whether the file was handled. Files that weren't are
+/// handed over again later, with a growing delay.
+///
+/// Watching stops when the returned watcher is dropped.
+pub fn watch(
+    folders: &[PathBuf],
+    mut on_ready: impl FnMut(PathBuf) -> bool + Send + 'static,
+) -> notify::Result<RecommendedWatcher> {
+    let (tx, rx) = mpsc::channel::<PathBuf>();
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event
+            && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+        {
+            for path in event.paths {
+                let _ = tx.send(path);
+            }
+        }
+    })?;
+
+    for folder in folders {
+        watcher.watch(folder, RecursiveMode::NonRecursive)?;
+    }
+
+    thread::spawn(move || {
+        let mut settler = Settler::default();
+
+        loop {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(path) => {
+                    if crate::is_pdf(&path) {
+                        settler.changed(path, Instant::now());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            for path in settler.ready(Instant::now()) {
+                if !on_ready(path.clone()) {
+                    settler.failed(path, Instant::now());
+                }
+            }
+        }
+    });
+
+    Ok(watcher)
+}
+
+/// Keeps track of changed files until they have stopped growing.
+#[derive(Default)]
+struct Settler {
+    /// Size at the last check and when it last changed, by path
+    pending: HashMap<PathBuf, (Option<u64>, Instant)>,
+    /// Files already handed over, so later events on them are ignored
+    /// unless their contents change again
+    done: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+    /// Failed hand-overs in a row, by path
+    failures: HashMap<PathBuf, u32>,
+}
+
+impl Settler {
+    /// Notes that `path` was created or written to.
+    fn changed(&mut self, path: PathBuf, now: Instant) {
+        self.failures.remove(&path);
+        self.pending.insert(path, (None, now));
+    }
+
+    /// Takes the files that have stopped changing and weren't handed over
+    /// as they are now.
+    fn ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut ready = Vec::new();
+        self.pending.retain(|path, (size, since)| {
+            if now.saturating_duration_since(*since) < SETTLE_TIME {
+                return true;
+            }
+
+            let Some(metadata) = fs::metadata(path).ok().filter(|m| m.is_file()) else {
+                return false;
+            };
+
+            if *size != Some(metadata.len()) {
+                *size = Some(metadata.len());
+                *since = now;
+                return true;
+            }
+
+            let stamp = (metadata.len(), metadata.modified().ok());
+            if self.done.get(path) != Some(&stamp) {
+                self.done.insert(path.clone(), stamp);
+                ready.push(path.clone());
+            }
+
+            false
+        });
+        ready
+    }
+
+    /// Notes that `path` could not be handled, so it is handed over again
+    /// once the retry delay has passed.
+    fn failed(&mut self, path: PathBuf, now: Instant) {
+        let failures = self.failures.entry(path.clone()).or_default();
+        let delay = RETRY_DELAY
+            .saturating_mul(1 << (*failures).min(16))
+            .min(MAX_RETRY_DELAY);
+        *failures += 1;
+
+        self.done.remove(&path);
+        // The file has to settle again after the delay, so start counting
+        // its settle time from then
+        self.pending.insert(path, (None, now + delay));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::Path;
+
+    fn write_file(path: &Path, size: usize) {
+        fs::write(path, vec![b'%'; size]).unwrap();
+    }
+
+    fn append(path: &Path, size: usize) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&vec![b'%'; size]).unwrap();
+    }
+
+    #[test]
+    fn files_are_ready_once_they_stop_growing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan.pdf");
+        write_file(&path, 10);
+
+        let start = Instant::now();
+        let mut settler = Settler::default();
+        settler.changed(path.clone(), start);
+
+        // Too early to look at the file at all
+        assert!(settler.ready(start).is_empty());
+        // First size check only records the size
+        assert!(settler.ready(start + SETTLE_TIME).is_empty());
+
+        // Still growing, so the settle time starts over
+        append(&path, 10);
+        assert!(settler.ready(start + SETTLE_TIME * 2).is_empty());
+        assert!(
+            settler
+                .ready(start + SETTLE_TIME * 2 + SETTLE_TIME / 2)
+                .is_empty()
+        );
+
+        assert_eq!(settler.ready(start + SETTLE_TIME * 3), vec![path]);
+        assert!(settler.pending.is_empty());
+    }
+
+    #[test]
+    fn unchanged_files_are_only_handed_over_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan.pdf");
+        write_file(&path, 10);
+
+        let start = Instant::now();
+        let mut settler = Settler::default();
+        settler.changed(path.clone(), start);
+        settler.ready(start + SETTLE_TIME);
+        assert_eq!(settler.ready(start + SETTLE_TIME * 2), vec![path.clone()]);
+
+        // Another event without a change in contents
+        settler.changed(path.clone(), start + SETTLE_TIME * 3);
+        settler.ready(start + SETTLE_TIME * 4);
+        assert!(settler.ready(start + SETTLE_TIME * 5).is_empty());
+
+        // Rewritten with different contents
+        append(&path, 10);
+        settler.changed(path.clone(), start + SETTLE_TIME * 6);
+        settler.ready(start + SETTLE_TIME * 7);
+        assert_eq!(settler.ready(start + SETTLE_TIME * 8), vec![path]);
+    }
+
+    #[test]
+    fn deleted_files_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan.pdf");
+
+        let start = Instant::now();
+        let mut settler = Settler::default();
+        settler.changed(path, start);
+
+        assert!(settler.ready(start + SETTLE_TIME).is_empty());
+        assert!(settler.pending.is_empty());
+    }
+
+    #[test]
+    fn failed_files_are_retried_after_a_growing_delay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan.pdf");
+        write_file(&path, 10);
+
+        let start = Instant::now();
+        let mut settler = Settler::default();
+        settler.changed(path.clone(), start);
+        settler.ready(start + SETTLE_TIME);
+        assert_eq!(settler.ready(start + SETTLE_TIME * 2), vec![path.clone()]);
+
+        let failed_at = start + SETTLE_TIME * 2;
+        settler.failed(path.clone(), failed_at);
+        let retry_at = failed_at + RETRY_DELAY + SETTLE_TIME;
+        assert!(settler.ready(retry_at - SETTLE_TIME / 2).is_empty());
+        settler.ready(retry_at);
+        assert_eq!(settler.ready(retry_at + SETTLE_TIME), vec![path.clone()]);
+
+        // The second failure waits twice as long
+        let failed_at = retry_at + SETTLE_TIME;
+        settler.failed(path.clone(), failed_at);
+        let retry_at = failed_at + RETRY_DELAY * 2 + SETTLE_TIME;
+        assert!(settler.ready(retry_at - SETTLE_TIME / 2).is_empty());
+        settler.ready(retry_at);
+        assert_eq!(settler.ready(retry_at + SETTLE_TIME), vec![path]);
+    }
+}